@@ -0,0 +1,446 @@
+//! `--save-world`で受信したチャンクをAnvil形式(.mca)で保存する
+
+use crate::chunk::{self, Section};
+use crate::nbt::{self, compound, Tag};
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const SECTOR_BYTES: u64 = 4096;
+
+/// `minecraft:oak_stairs[facing=north]`のようなブロックステートの名前とプロパティ
+pub struct BlockStateName {
+    pub name: String,
+    pub properties: Vec<(String, String)>,
+}
+
+/// チャンク内のブロックエンティティ
+/// チェストの中身のようにクライアントに送られてこないデータは保存されない
+pub struct BlockEntity {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+    /// `minecraft:chest`のようなID
+    pub id: String,
+    /// パケットに載っていたNBT (看板の文字など) を`compound_payload`にしたもの
+    pub data: Vec<u8>,
+}
+
+/// 保存する1チャンク分のデータ
+pub struct AnvilChunk<'a> {
+    pub x: i32,
+    pub z: i32,
+    /// 一番下のセクションのブロックY座標
+    pub min_y: i32,
+    /// 下から順のセクション
+    pub sections: &'a [Section],
+    /// 一番下のセクションの1つ下から順に、セクションごとの空の明るさ (2048バイト)
+    pub sky_light: Vec<Option<Vec<u8>>>,
+    /// `sky_light`と同じ並びのブロックの明るさ
+    pub block_light: Vec<Option<Vec<u8>>>,
+    pub block_entities: Vec<BlockEntity>,
+}
+
+/// ワールドの保存先
+pub struct WorldSaver {
+    dir: PathBuf,
+    data_version: i32,
+}
+
+impl WorldSaver {
+    /// 保存先を用意し、`level.dat`が無ければ作る
+    pub fn new(dir: &Path, mc_version: &str) -> io::Result<Self> {
+        let data_version = data_version(mc_version).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("unknown data version for Minecraft {mc_version}"),
+            )
+        })?;
+        fs::create_dir_all(dir)?;
+        let saver = WorldSaver {
+            dir: dir.to_path_buf(),
+            data_version,
+        };
+        let level_dat = dir.join("level.dat");
+        if !level_dat.exists() {
+            saver.write_level_dat(&level_dat, mc_version)?;
+        }
+        Ok(saver)
+    }
+
+    /// `dimension`(`minecraft:overworld`など)のリージョンファイルにチャンクを書き込む
+    pub fn save_chunk(
+        &self,
+        dimension: &str,
+        chunk: &AnvilChunk,
+        block_state_name: &dyn Fn(u32) -> BlockStateName,
+        biome_name: &dyn Fn(u32) -> String,
+    ) -> io::Result<()> {
+        let region_dir = self.region_dir(dimension);
+        fs::create_dir_all(&region_dir)?;
+        let path = region_dir.join(format!(
+            "r.{}.{}.mca",
+            chunk.x.div_euclid(32),
+            chunk.z.div_euclid(32)
+        ));
+
+        let nbt = nbt::write_root(&self.chunk_nbt(chunk, block_state_name, biome_name));
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&nbt)?;
+        write_region_chunk(&path, chunk.x, chunk.z, &encoder.finish()?)
+    }
+
+    /// ディメンションごとのリージョンファイルの置き場所
+    fn region_dir(&self, dimension: &str) -> PathBuf {
+        match dimension {
+            "minecraft:overworld" => self.dir.join("region"),
+            "minecraft:the_nether" => self.dir.join("DIM-1").join("region"),
+            "minecraft:the_end" => self.dir.join("DIM1").join("region"),
+            _ => {
+                let (namespace, path) = dimension
+                    .split_once(':')
+                    .unwrap_or(("minecraft", dimension));
+                self.dir
+                    .join("dimensions")
+                    .join(namespace)
+                    .join(path)
+                    .join("region")
+            }
+        }
+    }
+
+    fn chunk_nbt(
+        &self,
+        chunk: &AnvilChunk,
+        block_state_name: &dyn Fn(u32) -> BlockStateName,
+        biome_name: &dyn Fn(u32) -> String,
+    ) -> Tag {
+        let min_section_y = chunk.min_y.div_euclid(16);
+        let light_at = |light: &[Option<Vec<u8>>], i: usize| light.get(i).cloned().flatten();
+
+        // 明るさはブロックのあるセクションの1つ上と1つ下にもある
+        let mut sections = Vec::new();
+        for i in 0..chunk.sections.len() + 2 {
+            let y = min_section_y - 1 + i as i32;
+            let mut entries = vec![("Y".to_string(), Tag::Byte(y as i8))];
+            if let Some(section) = i.checked_sub(1).and_then(|i| chunk.sections.get(i)) {
+                entries.push((
+                    "block_states".to_string(),
+                    block_states_nbt(&section.blocks, block_state_name),
+                ));
+                entries.push((
+                    "biomes".to_string(),
+                    biomes_nbt(&section.biomes, biome_name),
+                ));
+            }
+            if let Some(light) = light_at(&chunk.block_light, i) {
+                entries.push(("BlockLight".to_string(), Tag::ByteArray(light)));
+            }
+            if let Some(light) = light_at(&chunk.sky_light, i) {
+                entries.push(("SkyLight".to_string(), Tag::ByteArray(light)));
+            }
+            if entries.len() > 1 {
+                sections.push(Tag::Compound(entries));
+            }
+        }
+
+        let block_entities = chunk
+            .block_entities
+            .iter()
+            .map(|x| {
+                let entries = [
+                    ("id", Tag::String(x.id.clone())),
+                    ("x", Tag::Int(x.x)),
+                    ("y", Tag::Int(x.y)),
+                    ("z", Tag::Int(x.z)),
+                    ("keepPacked", Tag::Byte(0)),
+                ];
+                Tag::CompoundWithRaw(
+                    entries
+                        .into_iter()
+                        .map(|(name, tag)| (name.to_string(), tag))
+                        .collect(),
+                    x.data.clone(),
+                )
+            })
+            .collect();
+
+        compound([
+            ("DataVersion", Tag::Int(self.data_version)),
+            ("xPos", Tag::Int(chunk.x)),
+            ("yPos", Tag::Int(min_section_y)),
+            ("zPos", Tag::Int(chunk.z)),
+            ("Status", Tag::String("minecraft:full".to_string())),
+            ("LastUpdate", Tag::Long(0)),
+            ("InhabitedTime", Tag::Long(0)),
+            ("isLightOn", Tag::Byte(1)),
+            ("sections", Tag::List(sections)),
+            ("block_entities", Tag::List(block_entities)),
+            (
+                "structures",
+                compound([
+                    ("References", Tag::Compound(Vec::new())),
+                    ("starts", Tag::Compound(Vec::new())),
+                ]),
+            ),
+        ])
+    }
+
+    /// 何も生成されない最小限の`level.dat`を書く
+    fn write_level_dat(&self, path: &Path, mc_version: &str) -> io::Result<()> {
+        let level_name = self
+            .dir
+            .file_name()
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "world".to_string());
+        let last_played = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis() as i64)
+            .unwrap_or(0);
+        let void = || {
+            compound([
+                ("type", Tag::String("minecraft:flat".to_string())),
+                (
+                    "settings",
+                    compound([
+                        ("biome", Tag::String("minecraft:the_void".to_string())),
+                        ("layers", Tag::List(Vec::new())),
+                        ("features", Tag::Byte(0)),
+                        ("lakes", Tag::Byte(0)),
+                        ("structure_overrides", Tag::List(Vec::new())),
+                    ]),
+                ),
+            ])
+        };
+        let dimension = |kind: &str| {
+            compound([
+                ("type", Tag::String(kind.to_string())),
+                ("generator", void()),
+            ])
+        };
+
+        let data = compound([
+            ("DataVersion", Tag::Int(self.data_version)),
+            ("version", Tag::Int(19133)),
+            (
+                "Version",
+                compound([
+                    ("Id", Tag::Int(self.data_version)),
+                    ("Name", Tag::String(mc_version.to_string())),
+                    ("Series", Tag::String("main".to_string())),
+                    ("Snapshot", Tag::Byte(0)),
+                ]),
+            ),
+            ("LevelName", Tag::String(level_name)),
+            ("LastPlayed", Tag::Long(last_played)),
+            ("GameType", Tag::Int(1)),
+            ("allowCommands", Tag::Byte(1)),
+            ("initialized", Tag::Byte(1)),
+            ("SpawnX", Tag::Int(0)),
+            ("SpawnY", Tag::Int(64)),
+            ("SpawnZ", Tag::Int(0)),
+            (
+                "WorldGenSettings",
+                compound([
+                    ("seed", Tag::Long(0)),
+                    ("generate_features", Tag::Byte(0)),
+                    ("bonus_chest", Tag::Byte(0)),
+                    (
+                        "dimensions",
+                        compound([
+                            ("minecraft:overworld", dimension("minecraft:overworld")),
+                            ("minecraft:the_nether", dimension("minecraft:the_nether")),
+                            ("minecraft:the_end", dimension("minecraft:the_end")),
+                        ]),
+                    ),
+                ]),
+            ),
+        ]);
+
+        let mut encoder = GzEncoder::new(File::create(path)?, Compression::default());
+        encoder.write_all(&nbt::write_root(&compound([("Data", data)])))?;
+        encoder.finish()?;
+        Ok(())
+    }
+}
+
+/// パケットのライトのマスクと配列をセクションごとに並べ直す
+/// `has_section(i)`はパケットのマスクのi番目のビット、`arrays`はビットが立っている分だけの配列
+pub fn light_arrays(
+    count: usize,
+    has_section: impl Fn(usize) -> bool,
+    arrays: Vec<Vec<u8>>,
+) -> Vec<Option<Vec<u8>>> {
+    let mut arrays = arrays.into_iter();
+    (0..count)
+        .map(|i| if has_section(i) { arrays.next() } else { None })
+        .collect()
+}
+
+/// 1.20.2以降のパケットの名前の無いルートのNBTから、コンパウンドの中身 (各要素と最後のTAG_End) を取り出す
+/// 空やコンパウンドでないものは空のコンパウンドとして扱う
+pub fn compound_payload(nbt: &[u8]) -> Vec<u8> {
+    match nbt.split_first() {
+        Some((10, payload)) if payload.last() == Some(&0) => payload.to_vec(),
+        _ => vec![0],
+    }
+}
+
+fn block_states_nbt(blocks: &[u32], block_state_name: &dyn Fn(u32) -> BlockStateName) -> Tag {
+    let (palette, _, data) = chunk::pack_palette(blocks, 4);
+    let palette = palette
+        .into_iter()
+        .map(|state| {
+            let state = block_state_name(state);
+            let mut entries = vec![("Name".to_string(), Tag::String(state.name))];
+            if !state.properties.is_empty() {
+                entries.push((
+                    "Properties".to_string(),
+                    Tag::Compound(
+                        state
+                            .properties
+                            .into_iter()
+                            .map(|(key, value)| (key, Tag::String(value)))
+                            .collect(),
+                    ),
+                ));
+            }
+            Tag::Compound(entries)
+        })
+        .collect();
+    paletted_nbt(palette, data)
+}
+
+fn biomes_nbt(biomes: &[u32], biome_name: &dyn Fn(u32) -> String) -> Tag {
+    let (palette, _, data) = chunk::pack_palette(biomes, 1);
+    let palette = palette
+        .into_iter()
+        .map(|biome| Tag::String(biome_name(biome)))
+        .collect();
+    paletted_nbt(palette, data)
+}
+
+fn paletted_nbt(palette: Vec<Tag>, data: Vec<u64>) -> Tag {
+    let mut entries = vec![("palette".to_string(), Tag::List(palette))];
+    if !data.is_empty() {
+        entries.push(("data".to_string(), Tag::LongArray(data)));
+    }
+    Tag::Compound(entries)
+}
+
+/// リージョンファイルの該当位置に圧縮済みのチャンクを書き込む
+/// 元の場所に収まらなければファイルの末尾に追記する
+#[allow(clippy::manual_div_ceil)]
+fn write_region_chunk(path: &Path, x: i32, z: i32, compressed: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    if file.metadata()?.len() < SECTOR_BYTES * 2 {
+        file.set_len(SECTOR_BYTES * 2)?;
+    }
+
+    // 長さ(4バイト) + 圧縮形式(1バイト) + 本体
+    let sectors = (compressed.len() as u64 + 5 + SECTOR_BYTES - 1) / SECTOR_BYTES;
+    if sectors > 255 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("chunk {x} {z} is too large to store in a region file"),
+        ));
+    }
+
+    let index = ((x.rem_euclid(32) + z.rem_euclid(32) * 32) * 4) as u64;
+    let mut location = [0u8; 4];
+    file.seek(SeekFrom::Start(index))?;
+    file.read_exact(&mut location)?;
+    let location = u32::from_be_bytes(location);
+    let (old_offset, old_sectors) = ((location >> 8) as u64, (location & 0xff) as u64);
+
+    let offset = if old_offset >= 2 && old_sectors >= sectors {
+        old_offset
+    } else {
+        (file.metadata()?.len() + SECTOR_BYTES - 1) / SECTOR_BYTES
+    };
+
+    let mut data = Vec::with_capacity((sectors * SECTOR_BYTES) as usize);
+    data.extend((compressed.len() as u32 + 1).to_be_bytes());
+    // 2はzlib
+    data.push(2);
+    data.extend(compressed);
+    data.resize((sectors * SECTOR_BYTES) as usize, 0);
+    file.seek(SeekFrom::Start(offset * SECTOR_BYTES))?;
+    file.write_all(&data)?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs() as u32)
+        .unwrap_or(0);
+    file.seek(SeekFrom::Start(index))?;
+    file.write_all(&((offset as u32) << 8 | sectors as u32).to_be_bytes())?;
+    file.seek(SeekFrom::Start(SECTOR_BYTES + index))?;
+    file.write_all(&timestamp.to_be_bytes())?;
+    Ok(())
+}
+
+/// MCのバージョンに対応するセーブデータのDataVersion
+pub fn data_version(mc_version: &str) -> Option<i32> {
+    Some(match mc_version {
+        "1.19.2" => 3120,
+        "1.19.3" => 3218,
+        "1.19.4" => 3337,
+        "1.20.1" => 3465,
+        "1.20.2" => 3578,
+        "1.20.4" => 3700,
+        "1.20.5" => 3837,
+        "1.20.6" => 3839,
+        "1.21.1" => 3955,
+        "1.21.3" => 4082,
+        "1.21.4" => 4189,
+        "1.21.5" => 4325,
+        "1.21.6" => 4435,
+        "1.21.7" => 4438,
+        "1.21.8" => 4440,
+        "1.21.9" => 4554,
+        "1.21.10" => 4556,
+        "1.21.11" => 4671,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `{Text: "hi"}`をパケットと同じ名前の無いルートで書いたもの
+    const SIGN_NBT: &[u8] = &[10, 8, 0, 4, b'T', b'e', b'x', b't', 0, 2, b'h', b'i', 0];
+
+    #[test]
+    fn compound_payload_strips_root_tag() {
+        assert_eq!(compound_payload(SIGN_NBT), &SIGN_NBT[1..]);
+        assert_eq!(compound_payload(&[]), vec![0]);
+        assert_eq!(compound_payload(&[0]), vec![0]);
+        assert_eq!(compound_payload(&[8, 0, 0]), vec![0]);
+        assert_eq!(compound_payload(&SIGN_NBT[..5]), vec![0]);
+    }
+
+    #[test]
+    fn block_entity_keeps_packet_data() {
+        let tag = Tag::CompoundWithRaw(
+            vec![("id".to_string(), Tag::String("minecraft:sign".to_string()))],
+            compound_payload(SIGN_NBT),
+        );
+        let mut expected = vec![10, 0, 0, 8, 0, 2, b'i', b'd', 0, 14];
+        expected.extend(b"minecraft:sign");
+        expected.extend(&SIGN_NBT[1..]);
+        assert_eq!(nbt::write_root(&tag), expected);
+    }
+}
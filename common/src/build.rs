@@ -0,0 +1,29 @@
+//! 各バージョンのbuild.rsから呼ぶ処理
+
+use std::{env, fs, path::Path};
+
+/// `versions/{MC_VERSION}/Cargo.toml`からMCのバージョンとazaleaのrevを読み取り、
+/// `MC_VERSION`と`AZALEA_REV`としてコンパイル時の環境変数に埋め込む
+pub fn export_build_info() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR is not set");
+    let manifest_dir = Path::new(&manifest_dir);
+    let manifest_path = manifest_dir.join("Cargo.toml");
+
+    let mc_version = manifest_dir
+        .file_name()
+        .and_then(|x| x.to_str())
+        .unwrap_or("unknown");
+
+    let manifest = fs::read_to_string(&manifest_path).expect("failed to read Cargo.toml");
+    let azalea_rev = manifest
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("rev"))
+        .filter_map(|rest| rest.trim_start().strip_prefix('='))
+        .map(|value| value.trim().trim_matches('"'))
+        .next()
+        .unwrap_or("unknown");
+
+    println!("cargo:rerun-if-changed={}", manifest_path.display());
+    println!("cargo:rustc-env=MC_VERSION={mc_version}");
+    println!("cargo:rustc-env=AZALEA_REV={azalea_rev}");
+}
@@ -0,0 +1,475 @@
+//! `LevelChunkWithLight`のチャンクデータの読み取り
+
+use serde::{Deserialize, Serialize};
+
+/// 1セクションのブロック数 (16x16x16)
+pub const SECTION_BLOCKS: usize = 4096;
+/// 1セクションのバイオーム数 (4x4x4)
+pub const SECTION_BIOMES: usize = 64;
+/// パレットのインデックスやIDの1要素あたりの最大ビット数
+/// IDはu32で扱うので、これより大きいものは壊れたデータとみなす
+const MAX_BITS_PER_ENTRY: u8 = 32;
+
+/// パケットから読み取った1セクション分の中身
+pub struct Section {
+    pub block_count: i16,
+    /// YZX順のブロックステートID
+    pub blocks: Vec<u32>,
+    /// YZX順のバイオームID
+    pub biomes: Vec<u32>,
+}
+
+/// `chunk`イベントに載せるチャンクの中身
+#[derive(Serialize, Deserialize)]
+pub struct ChunkData {
+    /// 一番下のセクションのブロックY座標
+    pub min_y: i32,
+    /// ワールドの高さ (ブロック数)
+    pub height: u32,
+    /// 下から順のセクション
+    pub sections: Vec<ChunkSection>,
+}
+
+/// `chunk`イベントに載せる1セクション分のブロック
+#[derive(Serialize, Deserialize)]
+pub struct ChunkSection {
+    /// セクション単位のY座標
+    pub y: i32,
+    /// 空気以外のブロック数
+    pub block_count: i16,
+    /// ブロックステートIDのパレット
+    pub palette: Vec<u32>,
+    /// `data`の1要素あたりのビット数 (パレットが1種類なら0)
+    pub bits_per_entry: u8,
+    /// パレットのインデックスをYZX順に下位ビットから詰めたu64列を
+    /// ビッグエンディアンでbase64にしたもの
+    /// (1つのインデックスが2つのu64にまたがることはない)
+    pub data: String,
+}
+
+/// 読み取ったセクションを`chunk`イベント用に変換する
+pub fn chunk_data(min_y: i32, height: u32, sections: &[Section]) -> ChunkData {
+    let min_section_y = min_y.div_euclid(16);
+    ChunkData {
+        min_y,
+        height,
+        sections: sections
+            .iter()
+            .enumerate()
+            .map(|(i, section)| encode_section(min_section_y + i as i32, section))
+            .collect(),
+    }
+}
+
+/// チャンクデータから`section_count`個のセクションを読み取る
+/// `data_length_prefixed`は1.21.4以前のようにパレットのデータ配列に長さが付いているか
+pub fn read_sections(
+    data: &[u8],
+    section_count: usize,
+    data_length_prefixed: bool,
+) -> Result<Vec<Section>, String> {
+    let mut reader = Reader { data, pos: 0 };
+    let mut sections = Vec::with_capacity(section_count);
+    for i in 0..section_count {
+        let read = |reader: &mut Reader| -> Result<Section, String> {
+            let block_count = reader.read_i16()?;
+            let blocks = reader.read_paletted_container(
+                SECTION_BLOCKS,
+                PaletteKind::Blocks,
+                data_length_prefixed,
+            )?;
+            let biomes = reader.read_paletted_container(
+                SECTION_BIOMES,
+                PaletteKind::Biomes,
+                data_length_prefixed,
+            )?;
+            Ok(Section {
+                block_count,
+                blocks,
+                biomes,
+            })
+        };
+        sections.push(read(&mut reader).map_err(|e| format!("section {i}: {e}"))?);
+    }
+    Ok(sections)
+}
+
+/// 値の列をパレットと詰めたインデックスにする
+/// パレットが2種類以上のときは1要素あたり最低`min_bits`ビットを使う
+/// 返り値は(パレット, 1要素あたりのビット数, 詰めたu64列)
+pub fn pack_palette(values: &[u32], min_bits: u8) -> (Vec<u32>, u8, Vec<u64>) {
+    let mut palette: Vec<u32> = Vec::new();
+    let indices: Vec<u64> = values
+        .iter()
+        .map(|value| match palette.iter().position(|x| x == value) {
+            Some(index) => index as u64,
+            None => {
+                palette.push(*value);
+                (palette.len() - 1) as u64
+            }
+        })
+        .collect();
+    let bits_per_entry = match bits_for(palette.len()) {
+        0 => 0,
+        bits => bits.max(min_bits),
+    };
+    (
+        palette,
+        bits_per_entry,
+        pack_indices(&indices, bits_per_entry),
+    )
+}
+
+/// インデックスを下位ビットから`bits_per_entry`ずつu64に詰める
+pub fn pack_indices(indices: &[u64], bits_per_entry: u8) -> Vec<u64> {
+    if bits_per_entry == 0 {
+        return Vec::new();
+    }
+    let per_long = 64 / bits_per_entry as usize;
+    indices
+        .chunks(per_long)
+        .map(|chunk| {
+            chunk.iter().enumerate().fold(0u64, |long, (i, index)| {
+                long | (index << (i * bits_per_entry as usize))
+            })
+        })
+        .collect()
+}
+
+/// `len`種類の値を区別するのに必要なビット数
+pub fn bits_for(len: usize) -> u8 {
+    if len <= 1 {
+        0
+    } else {
+        (usize::BITS - (len - 1).leading_zeros()) as u8
+    }
+}
+
+fn encode_section(y: i32, section: &Section) -> ChunkSection {
+    let (palette, bits_per_entry, data) = pack_palette(&section.blocks, 0);
+    let bytes: Vec<u8> = data.iter().flat_map(|x| x.to_be_bytes()).collect();
+    ChunkSection {
+        y,
+        block_count: section.block_count,
+        palette,
+        bits_per_entry,
+        data: base64(&bytes),
+    }
+}
+
+// 古いバージョンのツールチェインでもビルドできるようにdiv_ceilは使わない
+#[allow(clippy::manual_div_ceil)]
+fn base64(bytes: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - i * 8));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - i * 6)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[derive(Clone, Copy)]
+enum PaletteKind {
+    Blocks,
+    Biomes,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn read_bytes(&mut self, len: usize) -> Result<&[u8], String> {
+        let end = self.pos + len;
+        let bytes = self
+            .data
+            .get(self.pos..end)
+            .ok_or_else(|| "unexpected end of chunk data".to_string())?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_i16(&mut self) -> Result<i16, String> {
+        let bytes = self.read_bytes(2)?;
+        Ok(i16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, String> {
+        let bytes = self.read_bytes(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_varint(&mut self) -> Result<i32, String> {
+        let mut value = 0i32;
+        for i in 0..5 {
+            let byte = self.read_u8()?;
+            value |= ((byte & 0x7f) as i32) << (i * 7);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint is too long".to_string())
+    }
+
+    #[allow(clippy::manual_div_ceil)]
+    fn read_paletted_container(
+        &mut self,
+        len: usize,
+        kind: PaletteKind,
+        data_length_prefixed: bool,
+    ) -> Result<Vec<u32>, String> {
+        let bits_per_entry = self.read_u8()?;
+        if bits_per_entry == 0 {
+            let value = self.read_varint()? as u32;
+            if data_length_prefixed {
+                // 単一値のときも空の配列の長さが付いている
+                let data_len = self.read_varint()?;
+                for _ in 0..data_len {
+                    self.read_u64()?;
+                }
+            }
+            return Ok(vec![value; len]);
+        }
+        if bits_per_entry > MAX_BITS_PER_ENTRY {
+            return Err(format!("bits per entry {bits_per_entry} is too large"));
+        }
+
+        let indirect = match kind {
+            PaletteKind::Blocks => bits_per_entry <= 8,
+            PaletteKind::Biomes => bits_per_entry <= 3,
+        };
+        // ブロックの間接パレットは4ビット未満でも4ビットとして扱われる
+        let bits_per_entry = match kind {
+            PaletteKind::Blocks if indirect => bits_per_entry.max(4),
+            _ => bits_per_entry,
+        };
+        let palette = if indirect {
+            let palette_len = self.read_varint()?;
+            (0..palette_len)
+                .map(|_| self.read_varint().map(|x| x as u32))
+                .collect::<Result<Vec<_>, _>>()?
+        } else {
+            Vec::new()
+        };
+
+        let per_long = 64 / bits_per_entry as usize;
+        let data_len = if data_length_prefixed {
+            self.read_varint()? as usize
+        } else {
+            (len + per_long - 1) / per_long
+        };
+        let data = (0..data_len)
+            .map(|_| self.read_u64())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mask = (1u64 << bits_per_entry) - 1;
+        (0..len)
+            .map(|i| {
+                let long = data
+                    .get(i / per_long)
+                    .ok_or_else(|| "paletted container data is too short".to_string())?;
+                let index = (long >> ((i % per_long) * bits_per_entry as usize)) & mask;
+                if indirect {
+                    palette
+                        .get(index as usize)
+                        .copied()
+                        .ok_or_else(|| format!("palette index {index} is out of range"))
+                } else {
+                    Ok(index as u32)
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_varint(buf: &mut Vec<u8>, mut value: u32) {
+        loop {
+            if value < 0x80 {
+                buf.push(value as u8);
+                return;
+            }
+            buf.push((value & 0x7f) as u8 | 0x80);
+            value >>= 7;
+        }
+    }
+
+    /// `pack_palette`の結果をパケットのパレット付きコンテナとして書く
+    fn write_container(buf: &mut Vec<u8>, values: &[u32], min_bits: u8, prefixed: bool) {
+        let (palette, bits_per_entry, data) = pack_palette(values, min_bits);
+        buf.push(bits_per_entry);
+        if bits_per_entry == 0 {
+            write_varint(buf, palette[0]);
+        } else {
+            write_varint(buf, palette.len() as u32);
+            for value in palette {
+                write_varint(buf, value);
+            }
+        }
+        if prefixed {
+            write_varint(buf, data.len() as u32);
+        }
+        for long in data {
+            buf.extend(long.to_be_bytes());
+        }
+    }
+
+    fn write_section(buf: &mut Vec<u8>, blocks: &[u32], biomes: &[u32], prefixed: bool) {
+        buf.extend(1234i16.to_be_bytes());
+        write_container(buf, blocks, 4, prefixed);
+        write_container(buf, biomes, 1, prefixed);
+    }
+
+    fn unpack(data: &[u64], bits_per_entry: u8, len: usize) -> Vec<u64> {
+        let per_long = 64 / bits_per_entry as usize;
+        let mask = (1u64 << bits_per_entry) - 1;
+        (0..len)
+            .map(|i| (data[i / per_long] >> ((i % per_long) * bits_per_entry as usize)) & mask)
+            .collect()
+    }
+
+    #[test]
+    fn bits_for_counts_needed_bits() {
+        assert_eq!(bits_for(0), 0);
+        assert_eq!(bits_for(1), 0);
+        assert_eq!(bits_for(2), 1);
+        assert_eq!(bits_for(3), 2);
+        assert_eq!(bits_for(4), 2);
+        assert_eq!(bits_for(5), 3);
+        assert_eq!(bits_for(16), 4);
+        assert_eq!(bits_for(17), 5);
+    }
+
+    #[test]
+    fn pack_palette_single_value() {
+        let (palette, bits_per_entry, data) = pack_palette(&[7; SECTION_BLOCKS], 4);
+        assert_eq!(palette, vec![7]);
+        assert_eq!(bits_per_entry, 0);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn pack_palette_round_trip() {
+        let values: Vec<u32> = (0..SECTION_BLOCKS as u32).map(|i| i % 5 * 100).collect();
+        let (palette, bits_per_entry, data) = pack_palette(&values, 4);
+        assert_eq!(palette, vec![0, 100, 200, 300, 400]);
+        // 5種類なら3ビットで足りるが`min_bits`が優先される
+        assert_eq!(bits_per_entry, 4);
+        assert_eq!(data.len(), SECTION_BLOCKS / 16);
+        let unpacked: Vec<u32> = unpack(&data, bits_per_entry, values.len())
+            .into_iter()
+            .map(|index| palette[index as usize])
+            .collect();
+        assert_eq!(unpacked, values);
+    }
+
+    #[test]
+    fn pack_indices_does_not_span_longs() {
+        // 64は5で割り切れないので1つのu64に12個ずつ入り、上位4ビットは使わない
+        let indices: Vec<u64> = (0..13).map(|i| i % 32).collect();
+        let data = pack_indices(&indices, 5);
+        assert_eq!(data.len(), 2);
+        assert_eq!(data[0] >> 60, 0);
+        assert_eq!(data[1], 12);
+        assert_eq!(unpack(&data, 5, indices.len()), indices);
+    }
+
+    #[test]
+    fn base64_matches_rfc4648() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64(&[0xff, 0xfe, 0xfd]), "//79");
+    }
+
+    #[test]
+    fn read_sections_round_trip() {
+        for prefixed in [true, false] {
+            let blocks: Vec<u32> = (0..SECTION_BLOCKS as u32).map(|i| i % 7).collect();
+            let biomes: Vec<u32> = (0..SECTION_BIOMES as u32).map(|i| i % 3).collect();
+            let mut buf = Vec::new();
+            write_section(&mut buf, &blocks, &biomes, prefixed);
+            write_section(&mut buf, &[1; SECTION_BLOCKS], &[2; SECTION_BIOMES], prefixed);
+
+            let sections = read_sections(&buf, 2, prefixed).unwrap();
+            assert_eq!(sections.len(), 2);
+            assert_eq!(sections[0].block_count, 1234);
+            assert_eq!(sections[0].blocks, blocks);
+            assert_eq!(sections[0].biomes, biomes);
+            assert_eq!(sections[1].blocks, vec![1; SECTION_BLOCKS]);
+            assert_eq!(sections[1].biomes, vec![2; SECTION_BIOMES]);
+        }
+    }
+
+    #[test]
+    fn read_sections_direct_palette() {
+        // 9ビット以上のブロックはパレットを使わずIDを直接詰める
+        let blocks: Vec<u32> = (0..SECTION_BLOCKS as u32).map(|i| i * 3 % 20000).collect();
+        let indices: Vec<u64> = blocks.iter().map(|x| *x as u64).collect();
+        let data = pack_indices(&indices, 15);
+        let mut buf = Vec::new();
+        buf.extend(0i16.to_be_bytes());
+        buf.push(15);
+        for long in data {
+            buf.extend(long.to_be_bytes());
+        }
+        buf.extend([0, 0]);
+
+        let sections = read_sections(&buf, 1, false).unwrap();
+        assert_eq!(sections[0].blocks, blocks);
+    }
+
+    #[test]
+    fn read_sections_rejects_oversized_bits_per_entry() {
+        for bits_per_entry in [33, 64, 65, 255] {
+            let mut buf = vec![0, 0, bits_per_entry];
+            buf.extend([0; 12]);
+            assert!(read_sections(&buf, 1, false).is_err());
+            assert!(read_sections(&buf, 1, true).is_err());
+        }
+    }
+
+    #[test]
+    fn read_sections_rejects_truncated_data() {
+        let mut buf = Vec::new();
+        write_section(&mut buf, &[0; SECTION_BLOCKS], &[0; SECTION_BIOMES], false);
+        let blocks: Vec<u32> = (0..SECTION_BLOCKS as u32).map(|i| i % 7).collect();
+        write_section(&mut buf, &blocks, &[0; SECTION_BIOMES], false);
+        buf.truncate(buf.len() - 100);
+        let error = read_sections(&buf, 2, false).err().unwrap();
+        assert!(error.starts_with("section 1:"), "{error}");
+    }
+
+    #[test]
+    fn read_sections_rejects_out_of_range_palette_index() {
+        let mut buf = Vec::new();
+        buf.extend(0i16.to_be_bytes());
+        // 4ビット、パレット1要素で、インデックス1を指す
+        buf.extend([4, 1, 5]);
+        buf.extend(vec![0x11; SECTION_BLOCKS / 2]);
+        buf.extend([0, 0]);
+        assert!(read_sections(&buf, 1, false).is_err());
+    }
+}
@@ -0,0 +1,369 @@
+//! コマンドライン引数・`--config`のTOMLファイル・`FLEXBOT_*`環境変数からの設定の読み込み
+//! 同じ項目はコマンドライン引数、設定ファイル、環境変数の順に優先する
+//! `--server`と`--host`のように一緒に決まる項目は、組ごとに同じ順で優先する
+
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// 値を読んだところ (優先するものほど小さい)
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Origin {
+    Cli,
+    File,
+    Env,
+}
+
+/// 設定の読み込み元
+pub struct Sources {
+    cli: pico_args::Arguments,
+    /// `group`で指定されたかを調べるためのコマンドライン引数
+    cli_args: Vec<OsString>,
+    /// 設定ファイルの内容 (読んだ項目から取り除く)
+    file: toml::value::Table,
+    file_path: String,
+    /// `FLEXBOT_*`環境変数
+    env: BTreeMap<String, String>,
+}
+
+impl Sources {
+    /// コマンドライン引数を読み、`--config`か`FLEXBOT_CONFIG`があれば設定ファイルも読む
+    pub fn from_env() -> Result<Sources, String> {
+        let env = std::env::vars()
+            .filter(|(name, _)| name.starts_with("FLEXBOT_"))
+            .collect();
+        Sources::new(std::env::args_os().skip(1).collect(), env)
+    }
+
+    /// `cli_args`はプログラム名を除いたコマンドライン引数、`env`は`FLEXBOT_*`環境変数
+    pub(crate) fn new(
+        cli_args: Vec<OsString>,
+        env: BTreeMap<String, String>,
+    ) -> Result<Sources, String> {
+        let mut cli = pico_args::Arguments::from_vec(cli_args.clone());
+        let path: Option<String> = cli
+            .opt_value_from_str("--config")
+            .map_err(|e| format!("invalid --config: {e}"))?;
+        let path = path.or_else(|| env.get("FLEXBOT_CONFIG").cloned());
+        let file = match &path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| format!("failed to read config file {path}: {e}"))?;
+                toml::from_str(&text).map_err(|e| format!("invalid config file {path}: {e}"))?
+            }
+            None => toml::value::Table::new(),
+        };
+        Ok(Sources {
+            cli,
+            cli_args,
+            file,
+            file_path: path.unwrap_or_default(),
+            env,
+        })
+    }
+
+    /// 一緒に決まる項目の組 (`(key, flag)`の一覧) を、どれかを指定している中で最も優先される
+    /// 読み込み元だけから読むようにする
+    /// 例えばコマンドライン引数の`--server`は設定ファイルの`host`を上書きする
+    pub fn group(&mut self, items: &[(&'static str, &'static str)]) {
+        let in_cli = items
+            .iter()
+            .any(|(_, flag)| self.cli_args.iter().any(|arg| arg == flag));
+        let in_file = items.iter().any(|(key, _)| self.file.contains_key(*key));
+        for (key, _) in items {
+            if in_cli {
+                // 上書きされた設定ファイルの項目は未知の項目として扱わない
+                self.file.remove(*key);
+            }
+            if in_cli || in_file {
+                self.env.remove(&env_name(key));
+            }
+        }
+    }
+
+    /// `value`で読む前に、その項目をどこから読むことになるかを調べる
+    pub fn origin(&self, key: &'static str, flag: &'static str) -> Option<Origin> {
+        if self.cli_args.iter().any(|arg| arg == flag) {
+            Some(Origin::Cli)
+        } else if self.file.contains_key(key) {
+            Some(Origin::File)
+        } else if self.env.contains_key(&env_name(key)) {
+            Some(Origin::Env)
+        } else {
+            None
+        }
+    }
+
+    /// `flag`・設定ファイルの`key`・`FLEXBOT_KEY`のどれかで指定された値
+    pub fn value<T>(&mut self, key: &'static str, flag: &'static str) -> Result<Option<T>, String>
+    where
+        T: FromStr + DeserializeOwned,
+        T::Err: Display,
+    {
+        self.value_with(key, flag, |s| s.parse().map_err(|e: T::Err| e.to_string()))
+    }
+
+    /// `value`と同じだが、コマンドライン引数と環境変数の文字列を`parse`で読む
+    pub fn value_with<T: DeserializeOwned>(
+        &mut self,
+        key: &'static str,
+        flag: &'static str,
+        parse: fn(&str) -> Result<T, String>,
+    ) -> Result<Option<T>, String> {
+        let cli = self
+            .cli
+            .opt_value_from_fn(flag, parse)
+            .map_err(|e| format!("invalid {flag}: {e}"))?;
+        if cli.is_some() {
+            // 上書きされた設定ファイルの項目は未知の項目として扱わない
+            self.file.remove(key);
+            return Ok(cli);
+        }
+        self.fallback(key, parse)
+    }
+
+    /// 設定ファイルの`key`か`FLEXBOT_KEY`で指定された値
+    fn fallback<T: DeserializeOwned>(
+        &mut self,
+        key: &'static str,
+        parse: fn(&str) -> Result<T, String>,
+    ) -> Result<Option<T>, String> {
+        if let Some(value) = self.file.remove(key) {
+            return value
+                .try_into()
+                .map(Some)
+                .map_err(|e| format!("invalid `{key}` in {}: {e}", self.file_path));
+        }
+        let env = env_name(key);
+        match self.env.get(&env) {
+            Some(value) => parse(value)
+                .map(Some)
+                .map_err(|e| format!("invalid {env}: {e}")),
+            None => Ok(None),
+        }
+    }
+
+    /// 値を取らないフラグ
+    /// 設定ファイルと環境変数では`true`・`false`で指定する
+    pub fn flag(&mut self, key: &'static str, flag: &'static str) -> Result<bool, String> {
+        if self.cli.contains(flag) {
+            self.file.remove(key);
+            return Ok(true);
+        }
+        Ok(self.fallback(key, parse_bool)?.unwrap_or(false))
+    }
+
+    /// 使われなかった引数や設定ファイルの項目があればエラーにする
+    pub fn finish(self) -> Result<(), String> {
+        if let Some(key) = self.file.keys().next() {
+            return Err(format!("unknown key `{key}` in {}", self.file_path));
+        }
+        if let Some(arg) = self.cli.finish().first() {
+            return Err(format!("unexpected argument {}", arg.to_string_lossy()));
+        }
+        Ok(())
+    }
+}
+
+fn env_name(key: &str) -> String {
+    format!("FLEXBOT_{}", key.to_uppercase())
+}
+
+fn parse_bool(s: &str) -> Result<bool, String> {
+    match s {
+        "true" | "1" => Ok(true),
+        "false" | "0" | "" => Ok(false),
+        _ => Err(format!("expected true or false but got {s}")),
+    }
+}
+
+/// `--server`の`host[:port]`
+#[derive(serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct ServerTarget {
+    pub host: String,
+    /// 省略されたらSRVレコードを引く
+    pub port: Option<u16>,
+}
+
+impl FromStr for ServerTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ServerTarget, String> {
+        // `[::1]:25565`のようなIPv6アドレス
+        if let Some(rest) = s.strip_prefix('[') {
+            let (host, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| format!("invalid server address: {s}"))?;
+            if host.is_empty() {
+                return Err(format!("invalid server address: {s}"));
+            }
+            let port = match rest.strip_prefix(':') {
+                Some(port) => Some(parse_port(port)?),
+                None if rest.is_empty() => None,
+                None => return Err(format!("invalid server address: {s}")),
+            };
+            return Ok(ServerTarget {
+                host: host.to_string(),
+                port,
+            });
+        }
+        let (host, port) = match s.rsplit_once(':') {
+            // コロンが2つ以上あるものは括弧の無いIPv6アドレスとみなす
+            Some((host, port)) if !host.contains(':') => (host, Some(parse_port(port)?)),
+            _ => (s, None),
+        };
+        if host.is_empty() {
+            return Err(format!("invalid server address: {s}"));
+        }
+        Ok(ServerTarget {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl TryFrom<String> for ServerTarget {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<ServerTarget, String> {
+        s.parse()
+    }
+}
+
+fn parse_port(s: &str) -> Result<u16, String> {
+    s.parse().map_err(|_| format!("invalid port: {s}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sources(cli: &[&str], file: &str, env: &[(&str, &str)]) -> Sources {
+        let cli_args: Vec<OsString> = cli.iter().map(OsString::from).collect();
+        Sources {
+            cli: pico_args::Arguments::from_vec(cli_args.clone()),
+            cli_args,
+            file: toml::from_str(file).unwrap(),
+            file_path: "flexbot.toml".to_string(),
+            env: env
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn cli_overrides_file_overrides_env() {
+        let env = [("FLEXBOT_PORT", "1")];
+        let mut all = sources(&["--port", "3"], "port = 2", &env);
+        assert_eq!(all.value::<u16>("port", "--port").unwrap(), Some(3));
+        all.finish().unwrap();
+        let mut file = sources(&[], "port = 2", &env);
+        assert_eq!(file.value::<u16>("port", "--port").unwrap(), Some(2));
+        let mut env = sources(&[], "", &env);
+        assert_eq!(env.value::<u16>("port", "--port").unwrap(), Some(1));
+    }
+
+    #[test]
+    fn group_takes_every_key_from_the_cli() {
+        let mut sources = sources(
+            &["--server", "cli:1"],
+            "host = \"filehost\"",
+            &[("FLEXBOT_HOST", "envhost")],
+        );
+        sources.group(&[("server", "--server"), ("host", "--host")]);
+        let server: Option<ServerTarget> = sources.value("server", "--server").unwrap();
+        assert_eq!(server.unwrap().host, "cli");
+        assert_eq!(sources.value::<String>("host", "--host").unwrap(), None);
+        // 上書きされた`host`は未知の項目にならない
+        sources.finish().unwrap();
+    }
+
+    #[test]
+    fn group_takes_every_key_from_the_file() {
+        let mut sources = sources(
+            &[],
+            "host = \"filehost\"",
+            &[("FLEXBOT_SERVER", "envserver")],
+        );
+        sources.group(&[("server", "--server"), ("host", "--host")]);
+        assert!(sources
+            .value::<ServerTarget>("server", "--server")
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            sources
+                .value::<String>("host", "--host")
+                .unwrap()
+                .as_deref(),
+            Some("filehost")
+        );
+    }
+
+    #[test]
+    fn group_leaves_other_keys_alone() {
+        let mut sources = sources(
+            &["--server", "cli"],
+            "port = 2",
+            &[("FLEXBOT_HOST", "envhost"), ("FLEXBOT_COUNT", "3")],
+        );
+        sources.group(&[("server", "--server"), ("host", "--host")]);
+        assert_eq!(sources.value::<u16>("port", "--port").unwrap(), Some(2));
+        assert_eq!(sources.value::<u32>("count", "--count").unwrap(), Some(3));
+    }
+
+    #[test]
+    fn finish_rejects_unknown_keys_and_arguments() {
+        assert!(sources(&[], "hots = \"x\"", &[]).finish().is_err());
+        assert!(sources(&["--hots", "x"], "", &[]).finish().is_err());
+    }
+
+    #[test]
+    fn flags_accept_booleans_from_file_and_env() {
+        let mut file = sources(&[], "reconnect = true", &[]);
+        assert!(file.flag("reconnect", "--reconnect").unwrap());
+        let mut env = sources(&[], "", &[("FLEXBOT_RECONNECT", "0")]);
+        assert!(!env.flag("reconnect", "--reconnect").unwrap());
+        let mut invalid = sources(&[], "", &[("FLEXBOT_RECONNECT", "yes")]);
+        assert!(invalid.flag("reconnect", "--reconnect").is_err());
+    }
+
+    fn server(s: &str) -> (String, Option<u16>) {
+        let target: ServerTarget = s.parse().unwrap();
+        (target.host, target.port)
+    }
+
+    #[test]
+    fn server_target_forms() {
+        assert_eq!(server("example.com"), ("example.com".to_string(), None));
+        assert_eq!(
+            server("example.com:25566"),
+            ("example.com".to_string(), Some(25566))
+        );
+        assert_eq!(server("127.0.0.1:1"), ("127.0.0.1".to_string(), Some(1)));
+        assert_eq!(server("[::1]:25565"), ("::1".to_string(), Some(25565)));
+        assert_eq!(server("[::1]"), ("::1".to_string(), None));
+        // 括弧の無いIPv6アドレスはポート無しとみなす
+        assert_eq!(server("2001:db8::1"), ("2001:db8::1".to_string(), None));
+    }
+
+    #[test]
+    fn server_target_rejects_invalid_addresses() {
+        for s in [
+            "",
+            ":25565",
+            "example.com:",
+            "example.com:65536",
+            "example.com:port",
+            "[::1",
+            "[]:25565",
+            "[::1]25565",
+            "[::1]:x",
+        ] {
+            assert!(s.parse::<ServerTarget>().is_err(), "{s}");
+        }
+    }
+}
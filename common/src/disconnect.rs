@@ -0,0 +1,146 @@
+//! `disconnect`イベントの切断理由の分類
+
+use serde::{Deserialize, Serialize};
+
+/// 切断されたときの接続の段階
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum DisconnectPhase {
+    #[serde(rename = "login")]
+    Login,
+    #[serde(rename = "configuration")]
+    Configuration,
+    #[serde(rename = "game")]
+    Game,
+}
+
+/// 再接続して意味があるかを判断するための切断理由の分類
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DisconnectCategory {
+    /// サーバーに蹴られた (理由が下のどれにも当てはまらないもの)
+    #[serde(rename = "kicked")]
+    Kicked,
+    #[serde(rename = "banned")]
+    Banned,
+    /// ホワイトリストに入っていない
+    #[serde(rename = "whitelist")]
+    Whitelist,
+    /// 理由の無いまま接続が切れた
+    #[serde(rename = "connection_lost")]
+    ConnectionLost,
+    #[serde(rename = "timeout")]
+    Timeout,
+}
+
+impl DisconnectCategory {
+    /// 再接続しても同じ理由で拒否されるだけのもの (`--reconnect`でも再接続しない)
+    pub fn is_permanent(self) -> bool {
+        matches!(self, DisconnectCategory::Banned | DisconnectCategory::Whitelist)
+    }
+}
+
+/// 切断理由のテキストコンポーネントから分類する
+/// バニラの翻訳キーを優先し、無ければプラグインのよくある文言で判断する
+pub fn categorize(json: Option<&serde_json::Value>, text: Option<&str>) -> DisconnectCategory {
+    let Some(text) = text else {
+        return DisconnectCategory::ConnectionLost;
+    };
+    let mut keys = Vec::new();
+    if let Some(json) = json {
+        translation_keys(json, &mut keys);
+    }
+    for key in keys {
+        if key.starts_with("multiplayer.disconnect.banned") {
+            return DisconnectCategory::Banned;
+        }
+        if key == "multiplayer.disconnect.not_whitelisted" {
+            return DisconnectCategory::Whitelist;
+        }
+        if key == "disconnect.timeout" || key == "multiplayer.disconnect.slow_login" {
+            return DisconnectCategory::Timeout;
+        }
+        if key.starts_with("disconnect.genericReason") || key == "disconnect.endOfStream" {
+            return DisconnectCategory::ConnectionLost;
+        }
+    }
+    let text = text.to_lowercase();
+    if text.contains("banned") {
+        DisconnectCategory::Banned
+    } else if text.contains("whitelist") || text.contains("white-list") {
+        DisconnectCategory::Whitelist
+    } else if text.contains("timed out") || text.contains("timeout") {
+        DisconnectCategory::Timeout
+    } else {
+        DisconnectCategory::Kicked
+    }
+}
+
+/// テキストコンポーネントに含まれる`translate`を全て集める
+fn translation_keys<'a>(json: &'a serde_json::Value, keys: &mut Vec<&'a str>) {
+    match json {
+        serde_json::Value::Object(object) => {
+            if let Some(serde_json::Value::String(key)) = object.get("translate") {
+                keys.push(key);
+            }
+            for value in object.values() {
+                translation_keys(value, keys);
+            }
+        }
+        serde_json::Value::Array(array) => {
+            for value in array {
+                translation_keys(value, keys);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn categorize_prefers_translation_keys() {
+        let banned = json!({
+            "translate": "multiplayer.disconnect.banned.reason",
+            "with": [{"text": "griefing"}],
+        });
+        assert_eq!(
+            categorize(Some(&banned), Some("You are banned from this server.")),
+            DisconnectCategory::Banned
+        );
+        let whitelist = json!({"translate": "multiplayer.disconnect.not_whitelisted"});
+        assert_eq!(
+            categorize(Some(&whitelist), Some("You are not white-listed on this server!")),
+            DisconnectCategory::Whitelist
+        );
+        let timeout = json!({"text": "", "extra": [{"translate": "disconnect.timeout"}]});
+        assert_eq!(
+            categorize(Some(&timeout), Some("Timed out")),
+            DisconnectCategory::Timeout
+        );
+    }
+
+    #[test]
+    fn categorize_falls_back_to_text() {
+        let plugin = json!({"text": "You have been banned by an operator"});
+        assert_eq!(
+            categorize(Some(&plugin), Some("You have been banned by an operator")),
+            DisconnectCategory::Banned
+        );
+        assert_eq!(
+            categorize(None, Some("Server closed")),
+            DisconnectCategory::Kicked
+        );
+        assert_eq!(categorize(None, None), DisconnectCategory::ConnectionLost);
+    }
+
+    #[test]
+    fn only_bans_and_whitelist_are_permanent() {
+        assert!(DisconnectCategory::Banned.is_permanent());
+        assert!(DisconnectCategory::Whitelist.is_permanent());
+        assert!(!DisconnectCategory::Kicked.is_permanent());
+        assert!(!DisconnectCategory::Timeout.is_permanent());
+        assert!(!DisconnectCategory::ConnectionLost.is_permanent());
+    }
+}
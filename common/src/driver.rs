@@ -0,0 +1,445 @@
+//! ボットを接続してから終了するまで動かす、azaleaのバージョンに依らない部分
+//! スウォームモードでの起動、再接続、終了の要求、終了コードはここで扱い、
+//! 各バージョンは`Session`としてazaleaでの接続と接続中の処理だけを実装する
+
+use crate::anvil::WorldSaver;
+use crate::disconnect::{DisconnectCategory, DisconnectPhase};
+use crate::{
+    movement, swarm, write_result_line, write_stdout_line, Args, ConnectStage, Signals, StdinEvent,
+    StdinMessage, StdoutEvent,
+};
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::process::ExitCode;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// `Session`のメソッドが返すFuture
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// 接続できなかった理由
+pub struct ConnectFailure {
+    /// 失敗した段階
+    pub stage: ConnectStage,
+    /// `connect_failed`の`message`
+    pub message: String,
+    /// ログイン中にサーバーに切断されたなら、その理由
+    pub disconnect: Option<LoginDisconnect>,
+}
+
+/// ログイン中に切断された理由 (`disconnect`の`json`と`reason`になる)
+pub struct LoginDisconnect {
+    pub json: Option<serde_json::Value>,
+    pub text: Option<String>,
+}
+
+/// バージョンごとのazaleaのクライアントを持つ、接続中のボット
+pub trait Session: Sized + Send + 'static {
+    /// `join`が返す接続 (クライアントとイベントの受け口など)
+    type Connection: Send;
+
+    /// `args`のサーバーに接続し、`connected`を書き出す
+    fn join(args: &Args) -> BoxFuture<'_, Result<Self::Connection, ConnectFailure>>;
+
+    /// 最初に接続できたときにボットを作る
+    fn new(args: Args, world_saver: Option<WorldSaver>, connection: Self::Connection) -> Self;
+
+    /// 再接続できた接続に入れ替え、接続ごとの状態を初期化する
+    fn reconnect(&mut self, connection: Self::Connection);
+
+    fn args(&self) -> &Args;
+
+    /// 切断されるか終了を要求されるまでイベントと標準入力を処理する
+    /// 終了を要求されたらその理由を返す
+    fn run<'a>(
+        &'a mut self,
+        stdin: &'a mut UnboundedReceiver<StdinMessage>,
+        signals: &'a mut Signals,
+    ) -> BoxFuture<'a, anyhow::Result<Option<String>>>;
+
+    /// 切断された後の後始末をし、切断理由の分類が分かっていれば返す
+    fn disconnected(&mut self) -> anyhow::Result<Option<DisconnectCategory>>;
+
+    /// 終了を要求されたのでサーバーから切断する
+    fn disconnect(&mut self);
+}
+
+/// `hello`を書き出して引数を読み、全てのボットが終了するまで動かす
+/// 引数はそれぞれ`hello`と`parse_args`に渡すもの
+pub async fn main<S: Session>(
+    mc_version: &'static str,
+    azalea_rev: &str,
+    unsupported: &[&str],
+    pathfinder: movement::Pathfinder,
+) -> anyhow::Result<ExitCode> {
+    write_stdout_line(&crate::hello(
+        mc_version,
+        azalea_rev,
+        unsupported,
+        pathfinder,
+    ))?;
+
+    let bots = match crate::parse_args(mc_version, unsupported) {
+        Ok(bots) => bots,
+        Err(e) => {
+            eprintln!("{e}");
+            write_stdout_line(&StdoutEvent::Exited {
+                reason: "invalid_arguments".to_string(),
+            })?;
+            return Ok(ExitCode::from(2));
+        }
+    };
+    // ボットが2体以上ならスウォームモードとして出力に`bot`を付ける
+    let tagged = bots.len() > 1;
+    let usernames: Vec<String> = bots.iter().map(|args| args.username.clone()).collect();
+    let signals = crate::spawn_signal_listener();
+    // 標準入力は再接続しても同じものを使い続ける
+    let stdins = crate::spawn_stdin_reader(&usernames);
+    let tasks: Vec<_> = bots
+        .into_iter()
+        .zip(stdins)
+        .map(|(args, stdin)| {
+            let signals = signals.clone();
+            let bot = tagged.then(|| args.username.clone());
+            let task = tokio::spawn(swarm::scope(bot.clone(), async move {
+                // 1体がエラーになっても他のボットは動かし続ける
+                let (exit_code, reason) = match run_bot::<S>(args, mc_version, stdin, signals).await
+                {
+                    Ok(exited) => exited,
+                    Err(e) => {
+                        eprintln!("{e:?}");
+                        (FAILURE, "error".to_string())
+                    }
+                };
+                write_stdout_line(&StdoutEvent::Exited { reason })?;
+                Ok::<_, io::Error>(exit_code)
+            }));
+            (bot, task)
+        })
+        .collect();
+    // 全てのボットが終了するまで待ち、最初に失敗したボットの終了コードで終わる
+    let mut exit_code = SUCCESS;
+    for (bot, task) in tasks {
+        let code = match task.await {
+            Ok(Ok(code)) => code,
+            // `exited`を書き出せなかった
+            Ok(Err(e)) => {
+                eprintln!("{e:?}");
+                FAILURE
+            }
+            // パニックした
+            Err(e) => {
+                eprintln!("{e}");
+                swarm::scope(bot, async {
+                    write_stdout_line(&StdoutEvent::Exited {
+                        reason: "error".to_string(),
+                    })
+                })
+                .await?;
+                FAILURE
+            }
+        };
+        exit_code = merge_exit_codes(exit_code, code);
+    }
+    Ok(ExitCode::from(exit_code))
+}
+
+const SUCCESS: u8 = 0;
+const FAILURE: u8 = 1;
+
+/// 先に終了したボットから順に終了コードをまとめる
+/// 最初に失敗したボットの終了コードを使う
+fn merge_exit_codes(merged: u8, code: u8) -> u8 {
+    if merged == SUCCESS {
+        code
+    } else {
+        merged
+    }
+}
+
+/// 接続してから終了するまでボットを動かし、終了コードと`exited`の理由を返す
+async fn run_bot<S: Session>(
+    args: Args,
+    mc_version: &str,
+    mut stdin: UnboundedReceiver<StdinMessage>,
+    mut signals: Signals,
+) -> anyhow::Result<(u8, String)> {
+    let world_saver = match &args.save_world {
+        Some(dir) => Some(WorldSaver::new(dir, mc_version)?),
+        None => None,
+    };
+    let joined = tokio::select! {
+        joined = async {
+            tokio::time::sleep(args.join_delay).await;
+            S::join(&args).await
+        } => joined,
+        reason = wait_for_quit(&mut stdin) => return Ok((SUCCESS, reason?)),
+        reason = signals.recv() => return Ok((SUCCESS, reason)),
+    };
+    let mut session = match joined {
+        Ok(connection) => S::new(args, world_saver, connection),
+        Err(failure) => {
+            connect_failed(&failure)?;
+            return Ok((failure.stage.exit_code(), "connect_failed".to_string()));
+        }
+    };
+
+    loop {
+        if let Some(reason) = session.run(&mut stdin, &mut signals).await? {
+            session.disconnect();
+            return Ok((SUCCESS, reason));
+        }
+        // BANやホワイトリストは繋ぎ直しても同じなので再接続しない
+        let category = session.disconnected()?;
+        if matches!(category, Some(category) if category.is_permanent()) {
+            break;
+        }
+
+        // `session`を借りたまま待つと`Session`に`Sync`が要るので複製して使う
+        let args = session.args().clone();
+        let Some(policy) = args.reconnect.clone() else {
+            break;
+        };
+        let mut attempt = 0;
+        let mut last_failure: Option<ConnectStage> = None;
+        let connection = loop {
+            attempt += 1;
+            if policy.gives_up(attempt) {
+                return Ok(match last_failure {
+                    Some(stage) => (stage.exit_code(), "connect_failed".to_string()),
+                    None => (SUCCESS, "disconnected".to_string()),
+                });
+            }
+            let delay = policy.delay(attempt);
+            write_stdout_line(&StdoutEvent::Reconnecting {
+                attempt,
+                delay_ms: delay.as_millis() as u64,
+            })?;
+            let joined = tokio::select! {
+                joined = async {
+                    tokio::time::sleep(delay).await;
+                    S::join(&args).await
+                } => joined,
+                reason = wait_for_quit(&mut stdin) => return Ok((SUCCESS, reason?)),
+                reason = signals.recv() => return Ok((SUCCESS, reason)),
+            };
+            match joined {
+                Ok(connection) => break connection,
+                Err(failure) => {
+                    let category = connect_failed(&failure)?;
+                    if matches!(category, Some(category) if category.is_permanent()) {
+                        return Ok((failure.stage.exit_code(), "connect_failed".to_string()));
+                    }
+                    last_failure = Some(failure.stage);
+                }
+            }
+        };
+        session.reconnect(connection);
+        write_stdout_line(&StdoutEvent::Reconnected { attempt })?;
+    }
+    Ok((SUCCESS, "disconnected".to_string()))
+}
+
+/// 接続していない間の標準入力を処理し、`quit`か標準入力が閉じたら終了の理由を返す
+/// 接続が要る他のイベントにはエラーを返す
+async fn wait_for_quit(stdin: &mut UnboundedReceiver<StdinMessage>) -> io::Result<String> {
+    loop {
+        let Some(StdinMessage { id, event, .. }) = stdin.recv().await else {
+            return Ok("stdin_closed".to_string());
+        };
+        if let StdinEvent::Quit { reason } = event {
+            write_result_line(id, Ok(()))?;
+            return Ok(reason.unwrap_or("quit".to_string()));
+        }
+        write_result_line(id, Err("not connected".to_string()))?;
+    }
+}
+
+/// 接続の失敗を`connect_failed`として書き出す
+/// ログイン中に切断されたなら、その前に`disconnect`を書き出して理由の分類を返す
+fn connect_failed(failure: &ConnectFailure) -> io::Result<Option<DisconnectCategory>> {
+    let category = match &failure.disconnect {
+        Some(disconnect) => Some(crate::write_disconnect_line(
+            disconnect.json.clone(),
+            disconnect.text.clone(),
+            DisconnectPhase::Login,
+        )?),
+        None => None,
+    };
+    write_stdout_line(&StdoutEvent::ConnectFailed {
+        stage: failure.stage,
+        message: failure.message.clone(),
+    })?;
+    Ok(category)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reconnect::ReconnectPolicy;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+    use tokio::sync::{mpsc, watch};
+
+    /// `join`が`flaky`を受け付けた回数
+    static FLAKY_JOINS: AtomicU32 = AtomicU32::new(0);
+
+    /// ユーザー名で振る舞いを決める偽のボット
+    /// `kicked`は1度蹴られて再接続してから終了を要求され、`banned`はBANされる
+    /// `flaky`は最初の接続だけ成功し、`whitelist`はログイン中に弾かれる
+    struct Fake {
+        args: Args,
+        connections: u32,
+    }
+
+    impl Session for Fake {
+        type Connection = ();
+
+        fn join(args: &Args) -> BoxFuture<'_, Result<(), ConnectFailure>> {
+            Box::pin(async move {
+                match args.username.as_str() {
+                    "flaky" if FLAKY_JOINS.fetch_add(1, Ordering::SeqCst) > 0 => {
+                        Err(ConnectFailure {
+                            stage: ConnectStage::Connect,
+                            message: "connection refused".to_string(),
+                            disconnect: None,
+                        })
+                    }
+                    "whitelist" => Err(ConnectFailure {
+                        stage: ConnectStage::Rejected,
+                        message: "disconnected".to_string(),
+                        disconnect: Some(LoginDisconnect {
+                            json: Some(
+                                json!({"translate": "multiplayer.disconnect.not_whitelisted"}),
+                            ),
+                            text: None,
+                        }),
+                    }),
+                    _ => Ok(()),
+                }
+            })
+        }
+
+        fn new(args: Args, _world_saver: Option<WorldSaver>, _connection: ()) -> Self {
+            Fake {
+                args,
+                connections: 1,
+            }
+        }
+
+        fn reconnect(&mut self, _connection: ()) {
+            self.connections += 1;
+        }
+
+        fn args(&self) -> &Args {
+            &self.args
+        }
+
+        fn run<'a>(
+            &'a mut self,
+            _stdin: &'a mut UnboundedReceiver<StdinMessage>,
+            _signals: &'a mut Signals,
+        ) -> BoxFuture<'a, anyhow::Result<Option<String>>> {
+            Box::pin(async move {
+                Ok((self.connections > 1).then(|| format!("quit after {}", self.connections)))
+            })
+        }
+
+        fn disconnected(&mut self) -> anyhow::Result<Option<DisconnectCategory>> {
+            Ok(match self.args.username.as_str() {
+                "banned" => Some(DisconnectCategory::Banned),
+                _ => Some(DisconnectCategory::Kicked),
+            })
+        }
+
+        fn disconnect(&mut self) {}
+    }
+
+    fn args(username: &str, reconnect: bool) -> Args {
+        Args {
+            username: username.to_string(),
+            host: "localhost".to_string(),
+            port: None,
+            chunk_data: false,
+            save_world: None,
+            reconnect: reconnect.then_some(ReconnectPolicy {
+                max_attempts: Some(2),
+                initial_delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+            }),
+            position_interval: None,
+            auto_respawn: false,
+            entities: false,
+            entity_metadata: None,
+            proxy: None,
+            join_delay: Duration::ZERO,
+        }
+    }
+
+    async fn run(username: &str, reconnect: bool) -> (u8, String) {
+        // 送り手を残して標準入力が閉じないようにする
+        let (_stdin_tx, stdin) = mpsc::unbounded_channel();
+        let (_signal_tx, signals) = watch::channel(None);
+        run_bot::<Fake>(
+            args(username, reconnect),
+            "1.21.11",
+            stdin,
+            Signals(signals),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_being_kicked() {
+        assert_eq!(
+            run("kicked", true).await,
+            (SUCCESS, "quit after 2".to_string())
+        );
+        assert_eq!(
+            run("kicked", false).await,
+            (SUCCESS, "disconnected".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_reconnect_after_a_ban() {
+        assert_eq!(
+            run("banned", true).await,
+            (SUCCESS, "disconnected".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn gives_up_with_the_last_failed_stage() {
+        assert_eq!(
+            run("flaky", true).await,
+            (
+                ConnectStage::Connect.exit_code(),
+                "connect_failed".to_string()
+            )
+        );
+        assert_eq!(FLAKY_JOINS.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn reports_the_stage_of_a_failed_first_join() {
+        assert_eq!(
+            run("whitelist", true).await,
+            (
+                ConnectStage::Rejected.exit_code(),
+                "connect_failed".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn first_failure_decides_the_exit_code() {
+        let merged = [SUCCESS, 11, FAILURE, SUCCESS]
+            .into_iter()
+            .fold(SUCCESS, merge_exit_codes);
+        assert_eq!(merged, 11);
+        assert_eq!(merge_exit_codes(SUCCESS, SUCCESS), SUCCESS);
+    }
+}
@@ -0,0 +1,98 @@
+//! エンティティの位置の追跡
+
+use std::collections::HashMap;
+
+/// 相対移動のパケットを絶対座標にするためにエンティティの位置を覚えておく
+#[derive(Default)]
+pub struct EntityTracker {
+    positions: HashMap<i32, [f64; 3]>,
+}
+
+impl EntityTracker {
+    /// スポーンやテレポートで位置が分かった
+    pub fn set(&mut self, id: i32, position: [f64; 3]) {
+        self.positions.insert(id, position);
+    }
+
+    /// `MoveEntity*`の1/4096ブロック単位の差分を反映して新しい位置を返す
+    /// 知らないエンティティなら`None`
+    pub fn move_by(&mut self, id: i32, delta: [i16; 3]) -> Option<[f64; 3]> {
+        let position = self.positions.get_mut(&id)?;
+        for (axis, delta) in position.iter_mut().zip(delta) {
+            *axis += delta as f64 / 4096.0;
+        }
+        Some(*position)
+    }
+
+    pub fn remove(&mut self, id: i32) {
+        self.positions.remove(&id);
+    }
+
+    /// 再接続したときなどに全て忘れる
+    pub fn clear(&mut self) {
+        self.positions.clear();
+    }
+}
+
+/// `--entity-metadata`の`0,2,3`のようなインデックスの一覧を読む
+pub fn parse_metadata_indices(s: &str) -> Result<Vec<u8>, String> {
+    s.split(',')
+        .map(|x| {
+            x.trim()
+                .parse()
+                .map_err(|_| format!("invalid metadata index: {x}"))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn move_by_scales_delta_to_blocks() {
+        let mut tracker = EntityTracker::default();
+        tracker.set(1, [10.0, 64.0, -5.0]);
+        assert_eq!(
+            tracker.move_by(1, [4096, -2048, 1024]),
+            Some([11.0, 63.5, -4.75])
+        );
+        // 差分は積み重なる
+        assert_eq!(
+            tracker.move_by(1, [-4096, 0, -1024]),
+            Some([10.0, 63.5, -5.0])
+        );
+        assert_eq!(
+            tracker.move_by(1, [i16::MAX, i16::MIN, 0]),
+            Some([10.0 + 32767.0 / 4096.0, 63.5 - 8.0, -5.0])
+        );
+    }
+
+    #[test]
+    fn move_by_ignores_unknown_entities() {
+        let mut tracker = EntityTracker::default();
+        assert_eq!(tracker.move_by(1, [4096, 0, 0]), None);
+        tracker.set(1, [0.0, 0.0, 0.0]);
+        tracker.remove(1);
+        assert_eq!(tracker.move_by(1, [4096, 0, 0]), None);
+        tracker.set(2, [0.0, 0.0, 0.0]);
+        tracker.clear();
+        assert_eq!(tracker.move_by(2, [4096, 0, 0]), None);
+    }
+
+    #[test]
+    fn metadata_indices() {
+        assert_eq!(parse_metadata_indices("0,2, 3"), Ok(vec![0, 2, 3]));
+        assert_eq!(parse_metadata_indices("7"), Ok(vec![7]));
+    }
+
+    #[test]
+    fn metadata_indices_reject_invalid_lists() {
+        assert!(parse_metadata_indices("").is_err());
+        assert!(parse_metadata_indices("0,,2").is_err());
+        assert!(parse_metadata_indices("0,").is_err());
+        assert!(parse_metadata_indices("a").is_err());
+        assert!(parse_metadata_indices("-1").is_err());
+        assert!(parse_metadata_indices("256").is_err());
+    }
+}
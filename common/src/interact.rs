@@ -0,0 +1,64 @@
+//! ブロックやコンテナの操作系のStdinEventで使う型
+
+use serde::{Deserialize, Serialize};
+
+/// ブロックの面
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum BlockFace {
+    #[serde(rename = "down")]
+    Down,
+    #[serde(rename = "up")]
+    Up,
+    #[serde(rename = "north")]
+    North,
+    #[serde(rename = "south")]
+    South,
+    #[serde(rename = "west")]
+    West,
+    #[serde(rename = "east")]
+    East,
+}
+
+impl BlockFace {
+    /// 面の外向きの法線
+    pub fn normal(self) -> [i32; 3] {
+        match self {
+            BlockFace::Down => [0, -1, 0],
+            BlockFace::Up => [0, 1, 0],
+            BlockFace::North => [0, 0, -1],
+            BlockFace::South => [0, 0, 1],
+            BlockFace::West => [-1, 0, 0],
+            BlockFace::East => [1, 0, 0],
+        }
+    }
+
+    /// ブロック`(x, y, z)`のこの面の中心の座標
+    /// バニラのクライアントと同じくクリックした位置として送る
+    pub fn center(self, x: i32, y: i32, z: i32) -> [f64; 3] {
+        let normal = self.normal();
+        [
+            x as f64 + 0.5 + normal[0] as f64 * 0.5,
+            y as f64 + 0.5 + normal[1] as f64 * 0.5,
+            z as f64 + 0.5 + normal[2] as f64 * 0.5,
+        ]
+    }
+}
+
+/// `click_slot`のクリックの種類 (バニラの`ClickType`)
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum ClickMode {
+    #[serde(rename = "pickup")]
+    Pickup,
+    #[serde(rename = "quick_move")]
+    QuickMove,
+    #[serde(rename = "swap")]
+    Swap,
+    #[serde(rename = "clone")]
+    Clone,
+    #[serde(rename = "throw")]
+    Throw,
+    #[serde(rename = "quick_craft")]
+    QuickCraft,
+    #[serde(rename = "pickup_all")]
+    PickupAll,
+}
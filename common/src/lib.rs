@@ -1,49 +1,1222 @@
+//! このボットは標準入出力でJSON RPCするのでその型定義
+
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+pub mod anvil;
+pub mod build;
+pub mod chunk;
+pub mod config;
+pub mod disconnect;
+pub mod driver;
+pub mod entity;
+pub mod interact;
+pub mod movement;
+mod nbt;
+pub mod proxy;
+pub mod reconnect;
+pub mod swarm;
+
+/// 標準入出力のプロトコルのバージョン
+/// 互換性の無い変更をしたら上げる
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// 対応しているStdinEventの`type`一覧
+pub const STDIN_EVENT_TYPES: &[&str] = &[
+    "chat",
+    "command",
+    "look",
+    "look_at",
+    "walk",
+    "jump",
+    "sprint",
+    "sneak",
+    "stop",
+    "goto",
+    "respawn",
+    "use_block",
+    "click_slot",
+    "close_container",
+    "quit",
+];
 
-///! このボットは標準入出力でJSON RPCするのでその型定義
+/// 対応しているStdoutEventの`type`一覧
+pub const STDOUT_EVENT_TYPES: &[&str] = &[
+    "hello",
+    "spawn",
+    "disconnect",
+    "connected",
+    "connect_failed",
+    "chunk",
+    "chunk_unload",
+    "block_update",
+    "multi_block_update",
+    "position",
+    "path_progress",
+    "path_completed",
+    "path_failed",
+    "health",
+    "death",
+    "respawn",
+    "inventory",
+    "slot_update",
+    "container_open",
+    "container_contents",
+    "entity_spawn",
+    "entity_move",
+    "entity_remove",
+    "entity_metadata",
+    "player_join",
+    "player_leave",
+    "player_update",
+    "tab_list",
+    "reconnecting",
+    "reconnected",
+    "chat",
+    "result",
+    "protocol_error",
+    "exited",
+];
+
+/// 対応しているコマンドライン引数の一覧
+/// 設定ファイルと環境変数では`--`を除いて`-`を`_`にした名前で指定する
+pub const OPTIONS: &[&str] = &[
+    "--config",
+    "--username",
+    "--usernames",
+    "--username-template",
+    "--count",
+    "--join-stagger-ms",
+    "--server",
+    "--host",
+    "--port",
+    "--chunk-data",
+    "--save-world",
+    "--reconnect",
+    "--reconnect-max-attempts",
+    "--reconnect-initial-delay-ms",
+    "--reconnect-max-delay-ms",
+    "--position-interval-ms",
+    "--auto-respawn",
+    "--entities",
+    "--entity-metadata",
+    "--proxy",
+];
+
+/// 標準入力の1行
+/// `id`を付けると処理後に同じ`id`で`result`が返ってくる
+/// ボットが2体以上のときは`bot`で送り先のユーザー名を指定する
+#[derive(Serialize, Deserialize)]
+pub struct StdinMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bot: Option<String>,
+    #[serde(flatten)]
+    pub event: StdinEvent,
+}
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
-pub enum StdinEvent {}
+pub enum StdinEvent {
+    /// チャットを送信する
+    #[serde(rename = "chat")]
+    Chat { message: String },
+    /// コマンドを送信する (先頭の`/`は省略可)
+    #[serde(rename = "command")]
+    Command { command: String },
+    /// 指定した向きを向く (度数法、`pitch`は下向きが正)
+    #[serde(rename = "look")]
+    Look { yaw: f32, pitch: f32 },
+    /// 指定した座標を目から見る
+    #[serde(rename = "look_at")]
+    LookAt { x: f64, y: f64, z: f64 },
+    /// 歩く
+    /// `ticks` (1以上) を省略すると`stop`するまで歩き続ける
+    #[serde(rename = "walk")]
+    Walk {
+        direction: movement::WalkDirection,
+        #[serde(default)]
+        ticks: Option<u32>,
+    },
+    /// 1回ジャンプする
+    #[serde(rename = "jump")]
+    Jump {},
+    /// 前方に走る
+    /// `ticks` (1以上) を省略すると`stop`するまで走り続ける
+    #[serde(rename = "sprint")]
+    Sprint {
+        #[serde(default)]
+        ticks: Option<u32>,
+    },
+    /// スニークする (`enabled: false`で解除)
+    #[serde(rename = "sneak")]
+    Sneak {
+        #[serde(default = "default_true")]
+        enabled: bool,
+    },
+    /// 歩く・走る・ジャンプ・`goto`を止める
+    #[serde(rename = "stop")]
+    Stop {},
+    /// 指定した座標から`range`ブロック以内まで歩く
+    /// 経路の決め方は`hello`の`pathfinder`で分かる
+    /// 進み具合は`path_progress`・`path_completed`・`path_failed`で報告される
+    #[serde(rename = "goto")]
+    Goto {
+        x: f64,
+        y: f64,
+        z: f64,
+        #[serde(default = "default_goto_range")]
+        range: f64,
+    },
+    /// 死亡画面からリスポーンする
+    #[serde(rename = "respawn")]
+    Respawn {},
+    /// ブロックの面を右クリックする
+    #[serde(rename = "use_block")]
+    UseBlock {
+        x: i32,
+        y: i32,
+        z: i32,
+        face: interact::BlockFace,
+    },
+    /// 開いているウィンドウのスロットをクリックする
+    /// `slot`が-999ならウィンドウの外のクリック
+    #[serde(rename = "click_slot")]
+    ClickSlot {
+        window_id: i32,
+        slot: i16,
+        #[serde(default)]
+        button: u8,
+        mode: interact::ClickMode,
+    },
+    /// 開いているコンテナを閉じる
+    #[serde(rename = "close_container")]
+    CloseContainer {},
+    /// 切断してプロセスを終了する
+    #[serde(rename = "quit")]
+    Quit {
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
+
+fn default_goto_range() -> f64 {
+    1.0
+}
+
+fn default_true() -> bool {
+    true
+}
 
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum StdoutEvent {
+    /// 起動直後に最初の行として出力される
+    #[serde(rename = "hello")]
+    Hello {
+        protocol_version: u32,
+        mc_version: String,
+        azalea_rev: String,
+        stdin_events: Vec<String>,
+        stdout_events: Vec<String>,
+        /// このバージョンで使えるコマンドライン引数
+        options: Vec<String>,
+        /// `goto`の経路の決め方
+        pathfinder: movement::Pathfinder,
+    },
     #[serde(rename = "spawn")]
     Spawn {},
     #[serde(rename = "disconnect")]
-    Disconnect { reason: String },
+    Disconnect {
+        /// プレーンテキストにした切断理由 (理由が無ければ`unknown`)
+        reason: String,
+        /// 切断理由のテキストコンポーネントのJSON (理由が無ければ`null`)
+        json: Option<serde_json::Value>,
+        phase: disconnect::DisconnectPhase,
+        category: disconnect::DisconnectCategory,
+    },
+    /// サーバーに接続した (再接続したときも出力される)
+    #[serde(rename = "connected")]
+    Connected {
+        host: String,
+        port: u16,
+        /// SRVレコードやDNSを解決した接続先の`ip:port`
+        resolved_address: String,
+    },
+    /// サーバーに接続できなかった
+    /// 再接続中でなければプロセスは`stage`ごとの終了コードで終了する
+    #[serde(rename = "connect_failed")]
+    ConnectFailed {
+        stage: ConnectStage,
+        message: String,
+    },
     #[serde(rename = "chunk")]
-    Chunk { x: i32, z: i32 },
+    Chunk {
+        x: i32,
+        z: i32,
+        /// `--chunk-data`を指定したときだけ付くチャンクの中身
+        #[serde(flatten)]
+        data: Option<chunk::ChunkData>,
+    },
+    /// 自分の位置と向き
+    #[serde(rename = "position")]
+    Position {
+        reason: PositionReason,
+        x: f64,
+        y: f64,
+        z: f64,
+        yaw: f32,
+        pitch: f32,
+        /// `minecraft:overworld`のようなディメンション名
+        dimension: String,
+        on_ground: bool,
+    },
+    /// `goto`の途中経過
+    #[serde(rename = "path_progress")]
+    PathProgress {
+        x: f64,
+        y: f64,
+        z: f64,
+        /// 目的地までの直線距離
+        remaining: f64,
+    },
+    /// `goto`の目的地に着いた
+    #[serde(rename = "path_completed")]
+    PathCompleted { x: f64, y: f64, z: f64 },
+    /// `goto`を諦めた
+    /// `reason`は`stuck`・`no_path`・`cancelled`・`disconnected`のどれか
+    /// (`no_path`は`hello`の`pathfinder`が`azalea`のときだけ)
+    #[serde(rename = "path_failed")]
+    PathFailed { reason: String },
+    /// 体力や満腹度が変わった
+    #[serde(rename = "health")]
+    Health {
+        health: f32,
+        food: u32,
+        saturation: f32,
+    },
+    /// 死亡した
+    #[serde(rename = "death")]
+    Death {
+        /// プレーンテキストにした死亡メッセージ
+        message: String,
+    },
+    /// リスポーンまたはディメンションを移動した
+    #[serde(rename = "respawn")]
+    Respawn { dimension: String },
+    /// プレイヤーのインベントリ全体 (空のスロットは含まない)
+    #[serde(rename = "inventory")]
+    Inventory { items: Vec<InventorySlot> },
+    /// ウィンドウの1スロットが変わった
+    #[serde(rename = "slot_update")]
+    SlotUpdate {
+        /// 0はプレイヤーのインベントリ、-1はカーソル
+        window_id: i32,
+        slot: u16,
+        /// 空になったら`null`
+        item: Option<ItemStack>,
+    },
+    /// コンテナのウィンドウが開いた
+    #[serde(rename = "container_open")]
+    ContainerOpen {
+        window_id: i32,
+        /// `minecraft:generic_9x3`のようなメニューの種類
+        kind: String,
+        /// プレーンテキストにしたタイトル
+        title: String,
+    },
+    /// プレイヤーのインベントリ以外のウィンドウの中身 (空のスロットは含まない)
+    #[serde(rename = "container_contents")]
+    ContainerContents {
+        window_id: i32,
+        items: Vec<InventorySlot>,
+    },
+    /// エンティティが現れた (`--entities`を指定したときだけ)
+    #[serde(rename = "entity_spawn")]
+    EntitySpawn {
+        id: i32,
+        uuid: String,
+        /// `minecraft:zombie`のようなエンティティの種類
+        kind: String,
+        x: f64,
+        y: f64,
+        z: f64,
+    },
+    /// エンティティが動いた (`--entities`を指定したときだけ)
+    #[serde(rename = "entity_move")]
+    EntityMove { id: i32, x: f64, y: f64, z: f64 },
+    /// エンティティが消えた (`--entities`を指定したときだけ)
+    #[serde(rename = "entity_remove")]
+    EntityRemove { ids: Vec<i32> },
+    /// エンティティのメタデータが変わった (`--entity-metadata`で指定したインデックスだけ)
+    #[serde(rename = "entity_metadata")]
+    EntityMetadata {
+        id: i32,
+        /// インデックスごとの値をDebug表示したもの
+        values: BTreeMap<u8, String>,
+    },
+    /// タブリストにプレイヤーが追加された
+    #[serde(rename = "player_join")]
+    PlayerJoin {
+        #[serde(flatten)]
+        player: PlayerInfo,
+    },
+    /// タブリストからプレイヤーが消えた
+    #[serde(rename = "player_leave")]
+    PlayerLeave {
+        #[serde(flatten)]
+        player: PlayerInfo,
+    },
+    /// タブリストのプレイヤーの情報が変わった
+    #[serde(rename = "player_update")]
+    PlayerUpdate {
+        #[serde(flatten)]
+        player: PlayerInfo,
+    },
+    /// タブリストのヘッダーとフッター (プレーンテキスト)
+    #[serde(rename = "tab_list")]
+    TabList { header: String, footer: String },
+    /// 切断されたので再接続を待っている
+    #[serde(rename = "reconnecting")]
+    Reconnecting {
+        /// 何回目の再接続か (1始まり)
+        attempt: u32,
+        /// 再接続までの待ち時間
+        delay_ms: u64,
+    },
+    /// 再接続に成功した
+    #[serde(rename = "reconnected")]
+    Reconnected { attempt: u32 },
+    /// チャンクがアンロードされた
+    #[serde(rename = "chunk_unload")]
+    ChunkUnload { x: i32, z: i32 },
+    /// 1ブロックが更新された
+    #[serde(rename = "block_update")]
+    BlockUpdate {
+        #[serde(flatten)]
+        block: BlockChange,
+    },
+    /// 1セクション内の複数ブロックがまとめて更新された
+    #[serde(rename = "multi_block_update")]
+    MultiBlockUpdate { blocks: Vec<BlockChange> },
+    /// チャットを受信した
+    #[serde(rename = "chat")]
+    Chat {
+        kind: ChatKind,
+        /// 送信者のUUID (システムメッセージなどでは不明)
+        sender_uuid: Option<String>,
+        /// 送信者の名前 (システムメッセージなどでは不明)
+        sender: Option<String>,
+        /// プレーンテキストにしたメッセージ
+        message: String,
+        /// テキストコンポーネントのJSON
+        json: serde_json::Value,
+    },
+    /// `id`付きのStdinEventの処理結果
+    #[serde(rename = "result")]
+    Result {
+        id: serde_json::Value,
+        ok: bool,
+        error: Option<String>,
+    },
+    /// プロセスが終了する (最後の行)
+    /// `reason`は`quit`の理由、`stdin_closed`・`sigint`・`sigterm`・`disconnected`・`connect_failed`・
+    /// `invalid_arguments`・`error`のどれか (`error`は予期しないエラーで、内容は標準エラー出力に書かれる)
+    #[serde(rename = "exited")]
+    Exited { reason: String },
+    /// 標準入力の行を解釈できなかった
+    #[serde(rename = "protocol_error")]
+    ProtocolError {
+        /// 解釈できなかった行 (長いものは切り詰める)
+        line: String,
+        message: String,
+    },
+}
+
+/// ブロックの変化
+#[derive(Serialize, Deserialize)]
+pub struct BlockChange {
+    pub x: i32,
+    pub y: i32,
+    pub z: i32,
+    /// ブロックステートID
+    pub state: u32,
+    /// `minecraft:stone`のようなブロック名
+    pub block: String,
+}
+
+/// `position`イベントが出力された理由
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum PositionReason {
+    /// スポーンした
+    #[serde(rename = "spawn")]
+    Spawn,
+    /// サーバーにテレポートさせられた
+    #[serde(rename = "teleport")]
+    Teleport,
+    /// `--position-interval-ms`による定期的な報告
+    #[serde(rename = "periodic")]
+    Periodic,
+}
+
+/// 接続のどの段階で失敗したか
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum ConnectStage {
+    /// アドレスを解決できなかった
+    #[serde(rename = "resolve")]
+    Resolve,
+    /// TCP接続できなかった
+    #[serde(rename = "connect")]
+    Connect,
+    /// ハンドシェイクやログイン中の通信に失敗した
+    #[serde(rename = "handshake")]
+    Handshake,
+    /// ログイン中にサーバーに切断された (理由は直前の`phase`が`login`の`disconnect`で分かる)
+    #[serde(rename = "rejected")]
+    Rejected,
+}
+
+impl ConnectStage {
+    /// このステージで失敗したときのプロセスの終了コード
+    pub fn exit_code(self) -> u8 {
+        match self {
+            ConnectStage::Resolve => 10,
+            ConnectStage::Connect => 11,
+            ConnectStage::Handshake => 12,
+            ConnectStage::Rejected => 13,
+        }
+    }
+}
+
+/// タブリストのプレイヤー
+#[derive(Serialize, Deserialize)]
+pub struct PlayerInfo {
+    pub uuid: String,
+    pub name: String,
+    /// ミリ秒単位のping
+    pub latency: i32,
+    /// `survival`のようなゲームモード
+    pub gamemode: String,
+    /// タブリストでの表示名 (プレーンテキスト、無ければ`null`)
+    pub display_name: Option<String>,
+}
+
+/// スロットに入っているアイテム
+#[derive(Serialize, Deserialize)]
+pub struct ItemStack {
+    /// `minecraft:diamond`のようなアイテムID
+    pub id: String,
+    pub count: i32,
+    /// NBT (1.20.5未満) またはデータコンポーネント (1.20.5以降) をDebug表示したもの
+    pub data: String,
+}
+
+/// スロット番号付きのアイテム
+#[derive(Serialize, Deserialize)]
+pub struct InventorySlot {
+    pub slot: u16,
+    #[serde(flatten)]
+    pub item: ItemStack,
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum ChatKind {
+    #[serde(rename = "system")]
+    System,
+    #[serde(rename = "player")]
+    Player,
+    #[serde(rename = "disguised")]
+    Disguised,
+}
+
+/// `unsupported`に無く、`common`の都合でも使えなくないもの
+fn is_supported(name: &str, mc_version: &str, unsupported: &[&str]) -> bool {
+    if unsupported.contains(&name) {
+        return false;
+    }
+    // DataVersionが分からない新しいバージョンではワールドを保存できない
+    !(name == "--save-world" && anvil::data_version(mc_version).is_none())
+}
+
+/// `hello`イベントを作る
+/// `mc_version`と`azalea_rev`は`build::export_build_info`で埋め込んだものを渡す
+/// `unsupported`はそのバージョンで使えないStdinEventの`type`とコマンドライン引数
+/// `pathfinder`は`goto`の経路の決め方
+pub fn hello(
+    mc_version: &str,
+    azalea_rev: &str,
+    unsupported: &[&str],
+    pathfinder: movement::Pathfinder,
+) -> StdoutEvent {
+    let supported = |types: &[&str]| {
+        types
+            .iter()
+            .filter(|x| is_supported(x, mc_version, unsupported))
+            .map(|x| x.to_string())
+            .collect()
+    };
+    StdoutEvent::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        mc_version: mc_version.to_string(),
+        azalea_rev: azalea_rev.to_string(),
+        stdin_events: supported(STDIN_EVENT_TYPES),
+        stdout_events: supported(STDOUT_EVENT_TYPES),
+        options: supported(OPTIONS),
+        pathfinder,
+    }
+}
+
+/// `disconnect`イベントを書き出し、切断理由の分類を返す
+/// `json`は切断理由のテキストコンポーネント、`text`はそれをプレーンテキストにしたもの
+pub fn write_disconnect_line(
+    json: Option<serde_json::Value>,
+    text: Option<String>,
+    phase: disconnect::DisconnectPhase,
+) -> io::Result<disconnect::DisconnectCategory> {
+    let category = disconnect::categorize(json.as_ref(), text.as_deref());
+    write_stdout_line(&StdoutEvent::Disconnect {
+        reason: text.unwrap_or("unknown".to_string()),
+        json,
+        phase,
+        category,
+    })?;
+    Ok(category)
+}
+
+/// テキストコンポーネントなどをStdoutEventに載せるためにJSONに変換
+pub fn to_json_value<T: Serialize>(value: &T) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or(serde_json::Value::Null)
 }
 
 /// StdoutEventを標準出力用にシリアライズ
 /// 改行は含まない
+/// `swarm::scope`の中では`bot`を付ける
 pub fn serialize_stdout_line(event: &StdoutEvent) -> Vec<u8> {
-    serde_json::to_vec(event).unwrap()
+    #[derive(Serialize)]
+    struct Tagged<'a> {
+        bot: String,
+        #[serde(flatten)]
+        event: &'a StdoutEvent,
+    }
+    match swarm::current() {
+        Some(bot) => serde_json::to_vec(&Tagged { bot, event }).unwrap(),
+        None => serde_json::to_vec(event).unwrap(),
+    }
+}
+
+/// サーバーに弾かれないチャットかどうかを確認する
+/// バニラサーバーは256文字を超えるものや制御文字・`§`を含むものを受け付けない
+pub fn validate_chat_message(message: &str) -> std::result::Result<(), String> {
+    if message.is_empty() {
+        return Err("message is empty".to_string());
+    }
+    if message.chars().count() > 256 {
+        return Err("message is longer than 256 characters".to_string());
+    }
+    if message.chars().any(|c| c == '§' || c.is_control()) {
+        return Err("message contains illegal characters".to_string());
+    }
+    Ok(())
+}
+
+/// StdoutEventを1行として標準出力に書き出す
+pub fn write_stdout_line(event: &StdoutEvent) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    stdout.write_all(&serialize_stdout_line(event))?;
+    stdout.write_all(b"\n")?;
+    stdout.flush()
 }
 
-/// StdinEventを標準入力からデシリアライズ
-pub fn deserialize_stdin_line(line: &[u8]) -> Option<StdinEvent> {
-    serde_json::from_slice(&line).ok()
+/// StdinEventの処理結果を`result`として書き出す
+/// `id`が無いリクエストには何も返さない
+pub fn write_result_line(
+    id: Option<serde_json::Value>,
+    result: std::result::Result<(), String>,
+) -> io::Result<()> {
+    let Some(id) = id else {
+        return Ok(());
+    };
+    let (ok, error) = match result {
+        Ok(()) => (true, None),
+        Err(error) => (false, Some(error)),
+    };
+    write_stdout_line(&StdoutEvent::Result { id, ok, error })
 }
 
+/// StdinMessageを標準入力からデシリアライズ
+pub fn deserialize_stdin_line(line: &[u8]) -> serde_json::Result<StdinMessage> {
+    serde_json::from_slice(line)
+}
+
+/// 解釈できなかった行から`id`だけ取り出す (`result`で失敗を返すため)
+fn stdin_line_id(line: &[u8]) -> Option<serde_json::Value> {
+    match serde_json::from_slice::<serde_json::Value>(line) {
+        Ok(serde_json::Value::Object(mut object)) => object.remove("id").filter(|x| !x.is_null()),
+        _ => None,
+    }
+}
+
+/// ProtocolErrorに載せる行の最大文字数
+const PROTOCOL_ERROR_LINE_MAX_CHARS: usize = 256;
+
+fn protocol_error(line: &[u8], message: String) -> StdoutEvent {
+    let line = String::from_utf8_lossy(line);
+    let line = match line.char_indices().nth(PROTOCOL_ERROR_LINE_MAX_CHARS) {
+        Some((end, _)) => format!("{}...", &line[..end]),
+        None => line.into_owned(),
+    };
+    StdoutEvent::ProtocolError { line, message }
+}
+
+/// 標準入力を別スレッドで1行ずつ読み、`bots`のそれぞれにStdinEventとして流す
+/// `bot`が無い行はボットが1体だけならそのボットに流す
+/// 解釈できない行は`protocol_error`で報告し、`id`が読めれば失敗の`result`も返す
+/// 送り先が決まらない行は`id`があれば`result`、無ければ`protocol_error`で報告する
+/// 標準入力が閉じられるとチャンネルも閉じる
+pub fn spawn_stdin_reader(bots: &[String]) -> Vec<mpsc::UnboundedReceiver<StdinMessage>> {
+    let (txs, rxs): (Vec<_>, Vec<_>) = bots.iter().map(|_| mpsc::unbounded_channel()).unzip();
+    let bots = bots.to_vec();
+    std::thread::spawn(move || {
+        for line in io::stdin().lock().split(b'\n') {
+            let Ok(line) = line else { break };
+            let line = line.strip_suffix(b"\r").unwrap_or(&line);
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let message = match deserialize_stdin_line(line) {
+                Ok(message) => message,
+                Err(error) => {
+                    let _ = write_stdout_line(&protocol_error(line, error.to_string()));
+                    // `id`があれば、それを待っている側のために`result`も返す
+                    if let Some(id) = stdin_line_id(line) {
+                        let _ = write_result_line(Some(id), Err(error.to_string()));
+                    }
+                    continue;
+                }
+            };
+            match route_stdin_message(&bots, &txs, message) {
+                Ok(()) => {}
+                Err((message, error)) if message.id.is_some() => {
+                    let _ = write_result_line(message.id, Err(error));
+                }
+                Err((_, error)) => {
+                    let _ = write_stdout_line(&protocol_error(line, error));
+                }
+            }
+        }
+    });
+    rxs
+}
+
+/// `bot`で選んだボットに送る
+/// 送れなかったら、返すべきエラーと一緒に`message`を返す
+fn route_stdin_message(
+    bots: &[String],
+    txs: &[mpsc::UnboundedSender<StdinMessage>],
+    message: StdinMessage,
+) -> Result<(), (StdinMessage, String)> {
+    let index = match &message.bot {
+        Some(bot) => match bots.iter().position(|b| b == bot) {
+            Some(index) => index,
+            None => {
+                let error = format!("unknown bot {bot}");
+                return Err((message, error));
+            }
+        },
+        None if bots.len() == 1 => 0,
+        None => {
+            return Err((
+                message,
+                "`bot` is required when running multiple bots".to_string(),
+            ))
+        }
+    };
+    txs[index]
+        .send(message)
+        .map_err(|mpsc::error::SendError(message)| {
+            (message, format!("bot {} has exited", bots[index]))
+        })
+}
+
+/// SIGINT・SIGTERM (Windowsでは Ctrl+C) を受け取ったら`sigint`・`sigterm`を流す
+/// 複製したものは全て同じシグナルを受け取る
+#[derive(Clone)]
+pub struct Signals(watch::Receiver<Option<String>>);
+
+impl Signals {
+    /// シグナルを受け取るまで待ち、その名前を返す
+    /// 一度受け取った後は何度呼んでもすぐに返る
+    pub async fn recv(&mut self) -> String {
+        loop {
+            if let Some(signal) = self.0.borrow().clone() {
+                return signal;
+            }
+            if self.0.changed().await.is_err() {
+                // シグナルを待てなくなったら二度と返らない
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// シグナルの待ち受けを始める
+pub fn spawn_signal_listener() -> Signals {
+    let (tx, rx) = watch::channel(None);
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let signal = {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut terminate = signal(SignalKind::terminate()).ok();
+            tokio::select! {
+                Ok(()) = tokio::signal::ctrl_c() => "sigint",
+                Some(_) = async { terminate.as_mut()?.recv().await } => "sigterm",
+                else => return,
+            }
+        };
+        #[cfg(not(unix))]
+        let signal = match tokio::signal::ctrl_c().await {
+            Ok(()) => "sigint",
+            Err(_) => return,
+        };
+        let _ = tx.send(Some(signal.to_string()));
+    });
+    Signals(rx)
+}
+
+#[derive(Clone)]
 pub struct Args {
     pub username: String,
     pub host: String,
-    pub port: u16,
-}
-
-pub fn parse_args() -> Args {
-    let mut args = pico_args::Arguments::from_env();
-    let username: String = args
-        .value_from_str("--username")
-        .expect("--username is required");
-    let host: String = args.value_from_str("--host").expect("--host is required");
-    let port: u16 = args.value_from_str("--port").expect("--port is required");
-    Args {
-        username,
+    /// 省略されたら`_minecraft._tcp`のSRVレコードを引き、無ければ25565を使う
+    pub port: Option<u16>,
+    /// `chunk`イベントにチャンクの中身を載せるか
+    pub chunk_data: bool,
+    /// 受信したチャンクをAnvil形式で保存するディレクトリ
+    pub save_world: Option<PathBuf>,
+    /// 切断されたときの再接続の方針 (`--reconnect`を指定したときだけ)
+    pub reconnect: Option<reconnect::ReconnectPolicy>,
+    /// `position`イベントを定期的に出力する間隔
+    pub position_interval: Option<Duration>,
+    /// 死亡したら自動でリスポーンするか
+    pub auto_respawn: bool,
+    /// `entity_spawn`・`entity_move`・`entity_remove`を出力するか
+    pub entities: bool,
+    /// `entity_metadata`に載せるメタデータのインデックス
+    pub entity_metadata: Option<Vec<u8>>,
+    /// 接続に使うSOCKS5プロキシ
+    pub proxy: Option<proxy::ProxyConfig>,
+    /// 最初に接続するまでに待つ時間 (スウォームモードで接続をずらすため)
+    pub join_delay: Duration,
+}
+
+/// コマンドライン引数・設定ファイル・環境変数から`Args`を読む
+/// 詳しくは`config`モジュールを参照
+/// `--usernames`か`--username-template`で複数のボットを指定したときはボットごとの`Args`を返す
+/// `mc_version`と`unsupported`は`hello`に渡すのと同じもの
+pub fn parse_args(
+    mc_version: &str,
+    unsupported: &[&str],
+) -> std::result::Result<Vec<Args>, String> {
+    parse_sources(config::Sources::from_env()?, mc_version, unsupported)
+}
+
+fn parse_sources(
+    mut sources: config::Sources,
+    mc_version: &str,
+    unsupported: &[&str],
+) -> std::result::Result<Vec<Args>, String> {
+    sources.group(&[
+        ("username", "--username"),
+        ("usernames", "--usernames"),
+        ("username_template", "--username-template"),
+        ("count", "--count"),
+    ]);
+    sources.group(&[("server", "--server"), ("host", "--host")]);
+    let username: Option<String> = sources.value("username", "--username")?;
+    let usernames: Option<Vec<String>> =
+        sources.value_with("usernames", "--usernames", swarm::parse_usernames)?;
+    let username_template: Option<String> =
+        sources.value("username_template", "--username-template")?;
+    let count: Option<u32> = sources.value("count", "--count")?;
+    if [
+        username.is_some(),
+        usernames.is_some(),
+        username_template.is_some(),
+    ]
+    .iter()
+    .filter(|given| **given)
+    .count()
+        > 1
+    {
+        return Err(
+            "only one of --username, --usernames and --username-template can be used".to_string(),
+        );
+    }
+    if count.is_some() && username_template.is_none() {
+        return Err("--count can only be used with --username-template".to_string());
+    }
+    let usernames = match (username, usernames, username_template) {
+        (Some(username), _, _) => vec![username],
+        (_, Some(usernames), _) => usernames,
+        (_, _, Some(template)) => swarm::expand_template(&template, count.unwrap_or(1))?,
+        (None, None, None) => return Err(
+            "--username, --usernames or --username-template is required (or `username` in the config file, or FLEXBOT_USERNAME)"
+                .to_string(),
+        ),
+    };
+    swarm::validate_usernames(&usernames)?;
+    let join_stagger_ms: u64 = sources
+        .value("join_stagger_ms", "--join-stagger-ms")?
+        .unwrap_or(1000);
+    let server_origin = sources.origin("server", "--server");
+    let port_origin = sources.origin("port", "--port");
+    let server: Option<config::ServerTarget> = sources.value("server", "--server")?;
+    let host: Option<String> = sources.value("host", "--host")?;
+    let port: Option<u16> = sources.value("port", "--port")?;
+    let (host, port) = match (server, host) {
+        (Some(_), Some(_)) => return Err("--server and --host cannot be used together".to_string()),
+        // `server`に書かれたポートと`port`は、優先される読み込み元の方を使う
+        (Some(server), None) => match (server.port, port) {
+            (Some(_), Some(_)) if port_origin == server_origin => {
+                return Err("--server with a port and --port cannot be used together".to_string())
+            }
+            (Some(server_port), Some(port)) => (
+                server.host,
+                Some(if port_origin < server_origin {
+                    port
+                } else {
+                    server_port
+                }),
+            ),
+            (server_port, port) => (server.host, server_port.or(port)),
+        },
+        (None, Some(host)) => (host, port),
+        (None, None) => return Err(
+            "--server or --host is required (or `server` in the config file, or FLEXBOT_SERVER)"
+                .to_string(),
+        ),
+    };
+    let chunk_data = sources.flag("chunk_data", "--chunk-data")?;
+    let save_world: Option<PathBuf> = sources.value("save_world", "--save-world")?;
+    let reconnect = sources.flag("reconnect", "--reconnect")?;
+    let max_attempts: Option<u32> =
+        sources.value("reconnect_max_attempts", "--reconnect-max-attempts")?;
+    let initial_delay_ms: u64 = sources
+        .value("reconnect_initial_delay_ms", "--reconnect-initial-delay-ms")?
+        .unwrap_or(1000);
+    let max_delay_ms: u64 = sources
+        .value("reconnect_max_delay_ms", "--reconnect-max-delay-ms")?
+        .unwrap_or(60_000);
+    let reconnect = reconnect.then(|| reconnect::ReconnectPolicy {
+        max_attempts,
+        initial_delay: Duration::from_millis(initial_delay_ms),
+        max_delay: Duration::from_millis(max_delay_ms),
+    });
+    let position_interval_ms: Option<u64> =
+        sources.value("position_interval_ms", "--position-interval-ms")?;
+    let auto_respawn = sources.flag("auto_respawn", "--auto-respawn")?;
+    let entities = sources.flag("entities", "--entities")?;
+    let entity_metadata: Option<Vec<u8>> = sources.value_with(
+        "entity_metadata",
+        "--entity-metadata",
+        entity::parse_metadata_indices,
+    )?;
+    let proxy: Option<proxy::ProxyConfig> = sources.value("proxy", "--proxy")?;
+    sources.finish()?;
+    for (option, given) in [
+        ("--save-world", save_world.is_some()),
+        ("--proxy", proxy.is_some()),
+    ] {
+        if given && !is_supported(option, mc_version, unsupported) {
+            return Err(format!(
+                "{option} is not supported for Minecraft {mc_version}"
+            ));
+        }
+    }
+    let args = Args {
+        username: String::new(),
         host,
         port,
+        chunk_data,
+        save_world,
+        reconnect,
+        position_interval: position_interval_ms.map(Duration::from_millis),
+        auto_respawn,
+        entities,
+        entity_metadata,
+        proxy,
+        join_delay: Duration::ZERO,
+    };
+    let swarm = usernames.len() > 1;
+    Ok(usernames
+        .into_iter()
+        .enumerate()
+        .map(|(i, username)| Args {
+            // 同じディレクトリに書き込み合わないようにボットごとに分ける
+            save_world: match &args.save_world {
+                Some(dir) if swarm => Some(dir.join(&username)),
+                dir => dir.clone(),
+            },
+            join_delay: Duration::from_millis(join_stagger_ms.saturating_mul(i as u64)),
+            username,
+            ..args.clone()
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::ffi::OsString;
+
+    /// `file`を設定ファイルとして書き出し、`--config`で読ませる
+    fn parse(cli: &[&str], file: &str, env: &[(&str, &str)]) -> Result<Vec<Args>, String> {
+        let path = std::env::temp_dir().join(format!(
+            "flexbot-test-{}-{:?}.toml",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        std::fs::write(&path, file).unwrap();
+        let mut cli_args: Vec<OsString> = vec!["--config".into(), path.clone().into()];
+        cli_args.extend(cli.iter().map(OsString::from));
+        let env: BTreeMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let parsed =
+            config::Sources::new(cli_args, env).and_then(|x| parse_sources(x, "1.21.11", &[]));
+        std::fs::remove_file(path).unwrap();
+        parsed
+    }
+
+    #[test]
+    fn cli_server_overrides_env_host() {
+        let bots = parse(
+            &["--username", "bot", "--server", "cli:1"],
+            "",
+            &[("FLEXBOT_HOST", "envhost")],
+        )
+        .unwrap();
+        assert_eq!(bots[0].host, "cli");
+        assert_eq!(bots[0].port, Some(1));
+    }
+
+    #[test]
+    fn cli_server_overrides_file_host() {
+        let bots = parse(
+            &["--username", "bot", "--server", "cli"],
+            "host = \"filehost\"\nport = 2",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(bots[0].host, "cli");
+        assert_eq!(bots[0].port, Some(2));
+    }
+
+    #[test]
+    fn cli_server_port_overrides_file_and_env_port() {
+        let bots = parse(&["--username", "bot", "--server", "cli:1"], "port = 2", &[]).unwrap();
+        assert_eq!(bots[0].port, Some(1));
+        let bots = parse(
+            &["--username", "bot", "--server", "cli:1"],
+            "",
+            &[("FLEXBOT_PORT", "3")],
+        )
+        .unwrap();
+        assert_eq!(bots[0].port, Some(1));
+        // より優先される`--port`は`server`のポートを上書きする
+        let bots = parse(
+            &["--username", "bot", "--port", "4"],
+            "server = \"file:1\"",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(bots[0].port, Some(4));
+    }
+
+    #[test]
+    fn server_port_and_port_in_one_source_conflict() {
+        assert!(parse(
+            &["--username", "bot", "--server", "cli:1", "--port", "2"],
+            "",
+            &[]
+        )
+        .is_err());
+        assert!(parse(&["--username", "bot"], "server = \"file:1\"\nport = 2", &[]).is_err());
+    }
+
+    #[test]
+    fn cli_usernames_override_file_username() {
+        let bots = parse(
+            &["--usernames", "a,b", "--host", "localhost"],
+            "username = \"filebot\"",
+            &[],
+        )
+        .unwrap();
+        let names: Vec<&str> = bots.iter().map(|x| x.username.as_str()).collect();
+        assert_eq!(names, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn file_template_overrides_env_username() {
+        let bots = parse(
+            &["--host", "localhost"],
+            "username_template = \"bot{i}\"\ncount = 2",
+            &[("FLEXBOT_USERNAME", "envbot")],
+        )
+        .unwrap();
+        let names: Vec<&str> = bots.iter().map(|x| x.username.as_str()).collect();
+        assert_eq!(names, vec!["bot0", "bot1"]);
+    }
+
+    #[test]
+    fn one_source_still_rejects_conflicts() {
+        let error = parse(
+            &["--username", "bot", "--server", "a", "--host", "b"],
+            "",
+            &[],
+        )
+        .err()
+        .unwrap();
+        assert!(error.contains("cannot be used together"), "{error}");
+        let error = parse(
+            &["--host", "a"],
+            "username = \"a\"\nusernames = [\"b\", \"c\"]",
+            &[],
+        )
+        .err()
+        .unwrap();
+        assert!(error.contains("only one of"), "{error}");
+    }
+
+    #[test]
+    fn join_stagger_saturates() {
+        let bots = parse(
+            &[
+                "--usernames",
+                "a,b,c",
+                "--host",
+                "localhost",
+                "--join-stagger-ms",
+                &u64::MAX.to_string(),
+            ],
+            "",
+            &[],
+        )
+        .unwrap();
+        let delays: Vec<Duration> = bots.iter().map(|x| x.join_delay).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Duration::ZERO,
+                Duration::from_millis(u64::MAX),
+                Duration::from_millis(u64::MAX)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejected_lines_keep_their_id() {
+        let line = br#"{"id":5,"type":"walk","direction":"up"}"#;
+        assert!(deserialize_stdin_line(line).is_err());
+        assert_eq!(stdin_line_id(line), Some(serde_json::json!(5)));
+        assert_eq!(
+            stdin_line_id(br#"{"id":"a","type":"unknown"}"#),
+            Some(serde_json::json!("a"))
+        );
+        assert_eq!(stdin_line_id(br#"{"id":null,"type":"unknown"}"#), None);
+        assert_eq!(stdin_line_id(br#"{"type":"unknown"}"#), None);
+        assert_eq!(stdin_line_id(br#"[5]"#), None);
+        assert_eq!(stdin_line_id(b"not json"), None);
+    }
+
+    #[test]
+    fn lines_for_exited_bots_are_answered() {
+        let bots = vec!["a".to_string(), "b".to_string()];
+        let (txs, mut rxs): (Vec<_>, Vec<_>) =
+            bots.iter().map(|_| mpsc::unbounded_channel()).unzip();
+        let message = |line: &[u8]| deserialize_stdin_line(line).unwrap();
+
+        assert!(route_stdin_message(&bots, &txs, message(br#"{"bot":"a","type":"stop"}"#)).is_ok());
+        assert!(rxs[0].try_recv().is_ok());
+
+        rxs.truncate(1);
+        let (rejected, error) =
+            route_stdin_message(&bots, &txs, message(br#"{"id":7,"bot":"b","type":"stop"}"#))
+                .err()
+                .unwrap();
+        assert_eq!(rejected.id, Some(serde_json::json!(7)));
+        assert_eq!(error, "bot b has exited");
+
+        let (_, error) = route_stdin_message(&bots, &txs, message(br#"{"bot":"c","type":"stop"}"#))
+            .err()
+            .unwrap();
+        assert_eq!(error, "unknown bot c");
+        let (_, error) = route_stdin_message(&bots, &txs, message(br#"{"type":"stop"}"#))
+            .err()
+            .unwrap();
+        assert_eq!(error, "`bot` is required when running multiple bots");
+    }
+
+    #[test]
+    fn save_world_needs_a_known_data_version() {
+        let options = |mc_version| match hello(mc_version, "rev", &[], movement::Pathfinder::Azalea)
+        {
+            StdoutEvent::Hello { options, .. } => options,
+            _ => unreachable!(),
+        };
+        assert!(options("1.21.11").contains(&"--save-world".to_string()));
+        assert!(!options("1.99.0").contains(&"--save-world".to_string()));
+        assert!(options("1.99.0").contains(&"--proxy".to_string()));
+
+        let args = |mc_version| {
+            let sources = config::Sources::new(
+                [
+                    "--username",
+                    "bot",
+                    "--host",
+                    "localhost",
+                    "--save-world",
+                    "world",
+                ]
+                .iter()
+                .map(OsString::from)
+                .collect(),
+                BTreeMap::new(),
+            )
+            .unwrap();
+            parse_sources(sources, mc_version, &[])
+        };
+        assert!(args("1.21.11").is_ok());
+        let error = args("1.99.0").err().unwrap();
+        assert!(error.contains("--save-world"), "{error}");
+        let error = parse_sources(
+            config::Sources::new(
+                [
+                    "--username",
+                    "bot",
+                    "--host",
+                    "localhost",
+                    "--proxy",
+                    "socks5://p:1",
+                ]
+                .iter()
+                .map(OsString::from)
+                .collect(),
+                BTreeMap::new(),
+            )
+            .unwrap(),
+            "1.21.11",
+            &["--proxy"],
+        )
+        .err()
+        .unwrap();
+        assert!(error.contains("--proxy"), "{error}");
     }
 }
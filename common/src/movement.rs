@@ -0,0 +1,299 @@
+//! 移動系のStdinEventで使う型と計算
+
+use serde::{Deserialize, Serialize};
+
+/// `walk`の方向 (向いている方向が基準)
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub enum WalkDirection {
+    #[serde(rename = "forward")]
+    Forward,
+    #[serde(rename = "backward")]
+    Backward,
+    #[serde(rename = "left")]
+    Left,
+    #[serde(rename = "right")]
+    Right,
+    #[serde(rename = "forward_left")]
+    ForwardLeft,
+    #[serde(rename = "forward_right")]
+    ForwardRight,
+    #[serde(rename = "backward_left")]
+    BackwardLeft,
+    #[serde(rename = "backward_right")]
+    BackwardRight,
+}
+
+/// プレイヤーの目の高さ
+pub const EYE_HEIGHT: f64 = 1.62;
+
+/// `yaw`と`pitch`がサーバーに送れる値か確認する
+pub fn validate_rotation(yaw: f32, pitch: f32) -> Result<(), String> {
+    if !yaw.is_finite() || !pitch.is_finite() {
+        return Err("yaw and pitch must be finite".to_string());
+    }
+    if !(-90.0..=90.0).contains(&pitch) {
+        return Err("pitch must be between -90 and 90".to_string());
+    }
+    Ok(())
+}
+
+/// 足元の座標`from`から`to`を見るための(yaw, pitch)
+pub fn look_at_rotation(from: [f64; 3], to: [f64; 3]) -> (f32, f32) {
+    let dx = to[0] - from[0];
+    let dy = to[1] - (from[1] + EYE_HEIGHT);
+    let dz = to[2] - from[2];
+    let horizontal = (dx * dx + dz * dz).sqrt();
+    let yaw = (-dx).atan2(dz).to_degrees();
+    let pitch = (-dy).atan2(horizontal).to_degrees();
+    (yaw as f32, pitch as f32)
+}
+
+/// `walk`・`sprint`の`ticks`が1以上か確認する
+pub fn validate_ticks(ticks: Option<u32>) -> Result<(), String> {
+    match ticks {
+        Some(0) => Err("ticks must be at least 1".to_string()),
+        _ => Ok(()),
+    }
+}
+
+/// `goto`の経路の決め方 (`hello`で報告する)
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Pathfinder {
+    /// azaleaの経路探索 (`azalea::pathfinder`) で障害物を回り込む
+    #[serde(rename = "azalea")]
+    Azalea,
+    /// 目的地に向かってまっすぐ歩き、引っかかったらジャンプするだけ
+    /// 障害物を回り込むことはできない
+    #[serde(rename = "straight_line")]
+    StraightLine,
+}
+
+impl Pathfinder {
+    /// 目的地に近づけないまま何ティック経ったら諦めるか
+    /// azaleaの経路探索は遠回りすることがあるので長めに待つ
+    fn stuck_ticks(self) -> u32 {
+        match self {
+            Pathfinder::Azalea => 600,
+            Pathfinder::StraightLine => 200,
+        }
+    }
+}
+
+/// `goto`で何ティックごとに`path_progress`を出力するか
+const GOTO_PROGRESS_INTERVAL: u32 = 20;
+/// 1ティックでこれより動けていなければ段差などに引っかかっているとみなす
+const GOTO_BLOCKED_DISTANCE: f64 = 0.05;
+/// azaleaの経路探索が止まっていても、探索を始めてから何ティックまでは待つか
+/// (`goto`を送ってから探索が始まるまでに少しかかる)
+const PATHFINDER_START_TICKS: u32 = 5;
+/// azaleaの経路探索はブロックの中心までの距離で着いたと判断するので、その分のずれを許す
+const PATHFINDER_GOAL_TOLERANCE: f64 = 1.0;
+
+/// `goto`の1ティック分の操作
+pub enum GotoStep {
+    /// まだ着いていない
+    Walk {
+        /// まっすぐ歩くときの操作 (azaleaの経路探索に任せるときは`None`)
+        steer: Option<Steer>,
+        /// `path_progress`として報告する残りの距離
+        progress: Option<f64>,
+    },
+    Completed,
+    Failed(String),
+}
+
+/// `Pathfinder::StraightLine`で歩くための操作
+pub struct Steer {
+    /// 向きを`yaw`にして前に歩く
+    pub yaw: f32,
+    /// 引っかかっているのでジャンプする
+    pub jump: bool,
+}
+
+/// 実行中の`goto`の進み具合
+/// 近づけないまま一定時間経ったら`stuck`として諦める
+pub struct Goto {
+    pub target: [f64; 3],
+    pub range: f64,
+    pub pathfinder: Pathfinder,
+    ticks: u32,
+    best_distance: f64,
+    best_distance_tick: u32,
+    last_position: Option<[f64; 3]>,
+    next_progress_tick: u32,
+}
+
+impl Goto {
+    pub fn new(target: [f64; 3], range: f64, pathfinder: Pathfinder) -> Result<Goto, String> {
+        if target.iter().any(|x| !x.is_finite()) {
+            return Err("target must be finite".to_string());
+        }
+        if !range.is_finite() || range < 0.0 {
+            return Err("range must be a non-negative number".to_string());
+        }
+        Ok(Goto {
+            target,
+            range,
+            pathfinder,
+            ticks: 0,
+            best_distance: f64::INFINITY,
+            best_distance_tick: 0,
+            last_position: None,
+            next_progress_tick: GOTO_PROGRESS_INTERVAL,
+        })
+    }
+
+    /// 現在の足元の座標から次の操作を決める
+    /// `idle`はazaleaの経路探索が目的地を持たず、探索もしていないか
+    pub fn tick(&mut self, position: [f64; 3], idle: bool) -> GotoStep {
+        let distance = distance(position, self.target);
+        if distance <= self.range {
+            return GotoStep::Completed;
+        }
+
+        self.ticks += 1;
+        if self.pathfinder == Pathfinder::Azalea && idle && self.ticks > PATHFINDER_START_TICKS {
+            // 経路探索が終わったのに着いていなければ、行ける経路が無かった
+            return if distance <= self.range + PATHFINDER_GOAL_TOLERANCE {
+                GotoStep::Completed
+            } else {
+                GotoStep::Failed("no_path".to_string())
+            };
+        }
+        if distance < self.best_distance - 0.1 {
+            self.best_distance = distance;
+            self.best_distance_tick = self.ticks;
+        } else if self.ticks - self.best_distance_tick > self.pathfinder.stuck_ticks() {
+            return GotoStep::Failed("stuck".to_string());
+        }
+
+        let moved = self.last_position.map(|last| {
+            let dx = position[0] - last[0];
+            let dz = position[2] - last[2];
+            (dx * dx + dz * dz).sqrt()
+        });
+        self.last_position = Some(position);
+
+        let progress = (self.ticks >= self.next_progress_tick).then(|| {
+            self.next_progress_tick += GOTO_PROGRESS_INTERVAL;
+            distance
+        });
+        let steer = match self.pathfinder {
+            Pathfinder::Azalea => None,
+            Pathfinder::StraightLine => Some(Steer {
+                yaw: look_at_rotation(position, self.target).0,
+                jump: matches!(moved, Some(moved) if moved < GOTO_BLOCKED_DISTANCE),
+            }),
+        };
+        GotoStep::Walk { steer, progress }
+    }
+}
+
+fn distance(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a.iter()
+        .zip(b)
+        .map(|(a, b)| (a - b) * (a - b))
+        .sum::<f64>()
+        .sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_must_be_positive() {
+        assert!(validate_ticks(None).is_ok());
+        assert!(validate_ticks(Some(1)).is_ok());
+        assert!(validate_ticks(Some(0)).is_err());
+    }
+
+    #[test]
+    fn goto_completes_within_range() {
+        let mut goto = Goto::new([10.0, 64.0, 0.0], 1.0, Pathfinder::StraightLine).unwrap();
+        assert!(matches!(goto.tick([0.0, 64.0, 0.0], false), GotoStep::Walk { .. }));
+        assert!(matches!(goto.tick([9.5, 64.0, 0.0], false), GotoStep::Completed));
+    }
+
+    #[test]
+    fn straight_line_steers_and_jumps_when_blocked() {
+        let mut goto = Goto::new([0.0, 64.0, 10.0], 1.0, Pathfinder::StraightLine).unwrap();
+        let GotoStep::Walk { steer: Some(steer), .. } = goto.tick([0.0, 64.0, 0.0], false) else {
+            panic!("expected to walk");
+        };
+        // +Zを向くのはyaw 0
+        assert!(steer.yaw.abs() < 1e-3);
+        assert!(!steer.jump);
+        let GotoStep::Walk { steer: Some(steer), .. } = goto.tick([0.0, 64.0, 0.0], false) else {
+            panic!("expected to walk");
+        };
+        assert!(steer.jump);
+    }
+
+    #[test]
+    fn azalea_leaves_steering_to_the_pathfinder() {
+        let mut goto = Goto::new([0.0, 64.0, 10.0], 1.0, Pathfinder::Azalea).unwrap();
+        assert!(matches!(
+            goto.tick([0.0, 64.0, 0.0], false),
+            GotoStep::Walk { steer: None, .. }
+        ));
+    }
+
+    #[test]
+    fn azalea_reports_no_path_when_the_pathfinder_stops_early() {
+        let mut goto = Goto::new([0.0, 64.0, 10.0], 1.0, Pathfinder::Azalea).unwrap();
+        // 探索が始まるまでは止まっていても待つ
+        for _ in 0..PATHFINDER_START_TICKS {
+            assert!(matches!(goto.tick([0.0, 64.0, 0.0], true), GotoStep::Walk { .. }));
+        }
+        assert!(matches!(
+            goto.tick([0.0, 64.0, 0.0], true),
+            GotoStep::Failed(reason) if reason == "no_path"
+        ));
+    }
+
+    #[test]
+    fn azalea_accepts_the_block_centre_goal() {
+        let mut goto = Goto::new([0.0, 64.0, 10.0], 1.0, Pathfinder::Azalea).unwrap();
+        for _ in 0..=PATHFINDER_START_TICKS {
+            goto.tick([0.0, 64.0, 0.0], false);
+        }
+        assert!(matches!(goto.tick([0.0, 64.0, 8.5], true), GotoStep::Completed));
+    }
+
+    #[test]
+    fn goto_gives_up_when_stuck() {
+        for pathfinder in [Pathfinder::StraightLine, Pathfinder::Azalea] {
+            let mut goto = Goto::new([0.0, 64.0, 10.0], 1.0, pathfinder).unwrap();
+            let mut ticks = 0;
+            let reason = loop {
+                ticks += 1;
+                if let GotoStep::Failed(reason) = goto.tick([0.0, 64.0, 0.0], false) {
+                    break reason;
+                }
+            };
+            assert_eq!(reason, "stuck");
+            assert_eq!(ticks, pathfinder.stuck_ticks() + 2);
+        }
+    }
+
+    #[test]
+    fn goto_reports_progress_every_interval() {
+        let mut goto = Goto::new([0.0, 64.0, 1000.0], 1.0, Pathfinder::StraightLine).unwrap();
+        let reported: Vec<u32> = (1..=60)
+            .filter(|tick| {
+                matches!(
+                    goto.tick([0.0, 64.0, *tick as f64], false),
+                    GotoStep::Walk { progress: Some(_), .. }
+                )
+            })
+            .collect();
+        assert_eq!(reported, vec![20, 40, 60]);
+    }
+
+    #[test]
+    fn goto_rejects_invalid_targets() {
+        assert!(Goto::new([f64::NAN, 0.0, 0.0], 1.0, Pathfinder::Azalea).is_err());
+        assert!(Goto::new([0.0, 0.0, 0.0], -1.0, Pathfinder::Azalea).is_err());
+    }
+}
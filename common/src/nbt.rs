@@ -0,0 +1,97 @@
+//! ワールド保存用の最小限のNBT書き出し
+
+/// NBTのタグ
+pub enum Tag {
+    Byte(i8),
+    Int(i32),
+    Long(i64),
+    ByteArray(Vec<u8>),
+    String(String),
+    List(Vec<Tag>),
+    Compound(Vec<(String, Tag)>),
+    /// `Compound`の要素の後に、書き出し済みのコンパウンドの中身 (各要素と最後のTAG_End) を続けたもの
+    CompoundWithRaw(Vec<(String, Tag)>, Vec<u8>),
+    LongArray(Vec<u64>),
+}
+
+impl Tag {
+    fn id(&self) -> u8 {
+        match self {
+            Tag::Byte(_) => 1,
+            Tag::Int(_) => 3,
+            Tag::Long(_) => 4,
+            Tag::ByteArray(_) => 7,
+            Tag::String(_) => 8,
+            Tag::List(_) => 9,
+            Tag::Compound(_) | Tag::CompoundWithRaw(..) => 10,
+            Tag::LongArray(_) => 12,
+        }
+    }
+
+    fn write_payload(&self, buf: &mut Vec<u8>) {
+        match self {
+            Tag::Byte(x) => buf.push(*x as u8),
+            Tag::Int(x) => buf.extend(x.to_be_bytes()),
+            Tag::Long(x) => buf.extend(x.to_be_bytes()),
+            Tag::ByteArray(x) => {
+                buf.extend((x.len() as i32).to_be_bytes());
+                buf.extend(x);
+            }
+            Tag::String(x) => write_string(buf, x),
+            Tag::List(x) => {
+                // 空のリストはTAG_Endのリストとして書く
+                buf.push(x.first().map(Tag::id).unwrap_or(0));
+                buf.extend((x.len() as i32).to_be_bytes());
+                for tag in x {
+                    tag.write_payload(buf);
+                }
+            }
+            Tag::Compound(x) => {
+                write_entries(buf, x);
+                buf.push(0);
+            }
+            Tag::CompoundWithRaw(x, raw) => {
+                write_entries(buf, x);
+                buf.extend(raw);
+            }
+            Tag::LongArray(x) => {
+                buf.extend((x.len() as i32).to_be_bytes());
+                for long in x {
+                    buf.extend(long.to_be_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// 名前が空のルートコンパウンドとして書き出す
+pub fn write_root(root: &Tag) -> Vec<u8> {
+    let mut buf = vec![root.id()];
+    write_string(&mut buf, "");
+    root.write_payload(&mut buf);
+    buf
+}
+
+/// `(名前, タグ)`の列からコンパウンドを作る
+pub fn compound<const N: usize>(entries: [(&str, Tag); N]) -> Tag {
+    Tag::Compound(
+        entries
+            .into_iter()
+            .map(|(name, tag)| (name.to_string(), tag))
+            .collect(),
+    )
+}
+
+fn write_entries(buf: &mut Vec<u8>, entries: &[(String, Tag)]) {
+    for (name, tag) in entries {
+        buf.push(tag.id());
+        write_string(buf, name);
+        tag.write_payload(buf);
+    }
+}
+
+fn write_string(buf: &mut Vec<u8>, s: &str) {
+    // 本来はModified UTF-8だが、保存するのはASCIIの識別子くらいなので通常のUTF-8で書く
+    buf.extend((s.len() as u16).to_be_bytes());
+    buf.extend(s.as_bytes());
+}
@@ -0,0 +1,86 @@
+//! `--reconnect`の再接続ポリシー
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+/// 切断されたときの再接続の方針
+/// 試行ごとに待ち時間を倍にし、`max_delay`で頭打ちにする
+#[derive(Clone)]
+pub struct ReconnectPolicy {
+    /// 連続で失敗してよい回数 (`None`なら無制限)
+    pub max_attempts: Option<u32>,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// `attempt`回目(1始まり)の再接続を諦めるべきか
+    pub fn gives_up(&self, attempt: u32) -> bool {
+        matches!(self.max_attempts, Some(max) if attempt > max)
+    }
+
+    /// `attempt`回目(1始まり)の再接続までの待ち時間
+    /// 同時に切断された複数のボットが一斉に繋ぎ直さないよう、半分から全体の間でばらつかせる
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let delay = self
+            .initial_delay
+            .saturating_mul(1 << exponent)
+            .min(self.max_delay);
+        delay / 2 + delay.mul_f64(random_unit() / 2.0)
+    }
+}
+
+/// 0以上1未満の乱数
+fn random_unit() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: Option<u32>) -> ReconnectPolicy {
+        ReconnectPolicy {
+            max_attempts,
+            initial_delay: Duration::from_millis(1000),
+            max_delay: Duration::from_millis(60_000),
+        }
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let limited = policy(Some(3));
+        assert!(!limited.gives_up(1));
+        assert!(!limited.gives_up(3));
+        assert!(limited.gives_up(4));
+        assert!(!policy(None).gives_up(u32::MAX));
+    }
+
+    #[test]
+    fn delay_doubles_within_jitter() {
+        let policy = policy(None);
+        for (attempt, full_ms) in [(1, 1000), (2, 2000), (3, 4000), (6, 32_000)] {
+            for _ in 0..100 {
+                let delay = policy.delay(attempt);
+                assert!(delay >= Duration::from_millis(full_ms / 2), "{attempt}: {delay:?}");
+                assert!(delay <= Duration::from_millis(full_ms), "{attempt}: {delay:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn delay_is_capped_at_max_delay() {
+        let policy = policy(None);
+        for attempt in [7, 32, 100, u32::MAX] {
+            let delay = policy.delay(attempt);
+            assert!(delay >= Duration::from_millis(30_000), "{attempt}: {delay:?}");
+            assert!(delay <= Duration::from_millis(60_000), "{attempt}: {delay:?}");
+        }
+    }
+}
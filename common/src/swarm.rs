@@ -0,0 +1,59 @@
+//! 1つのプロセスで複数のボットを動かすスウォームモード
+//! ボットが2体以上のときは標準出力の各行に`bot`としてユーザー名を付け、
+//! 標準入力の各行は`bot`で送り先を選ぶ
+
+use std::future::Future;
+
+tokio::task_local! {
+    static BOT: String;
+}
+
+/// `template`の`{i}`を0から`count - 1`までの番号に置き換えたユーザー名の一覧
+pub fn expand_template(template: &str, count: u32) -> Result<Vec<String>, String> {
+    if count == 0 {
+        return Err("--count must be at least 1".to_string());
+    }
+    if count > 1 && !template.contains("{i}") {
+        return Err(
+            "--username-template must contain `{i}` when --count is more than 1".to_string(),
+        );
+    }
+    Ok((0..count)
+        .map(|i| template.replace("{i}", &i.to_string()))
+        .collect())
+}
+
+/// `--usernames`のカンマ区切りのユーザー名
+pub fn parse_usernames(s: &str) -> Result<Vec<String>, String> {
+    Ok(s.split(',').map(|name| name.trim().to_string()).collect())
+}
+
+/// ユーザー名が空でなく重複していないか確認する
+/// `bot`で送り先を選ぶので同じ名前のボットは置けない
+pub fn validate_usernames(names: &[String]) -> Result<(), String> {
+    if names.is_empty() {
+        return Err("no usernames are given".to_string());
+    }
+    for (i, name) in names.iter().enumerate() {
+        if name.is_empty() {
+            return Err("username is empty".to_string());
+        }
+        if names[..i].contains(name) {
+            return Err(format!("username {name} is duplicated"));
+        }
+    }
+    Ok(())
+}
+
+/// `bot`が`Some`なら、`f`の中で書き出すStdoutEventに`bot`を付ける
+pub async fn scope<F: Future>(bot: Option<String>, f: F) -> F::Output {
+    match bot {
+        Some(bot) => BOT.scope(bot, f).await,
+        None => f.await,
+    }
+}
+
+/// 今のタスクのボットの名前
+pub(crate) fn current() -> Option<String> {
+    BOT.try_with(Clone::clone).ok()
+}
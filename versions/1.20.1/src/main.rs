@@ -1,50 +1,958 @@
 use anyhow::Result;
-use azalea_client::{Account, Client};
-use azalea_protocol::ServerAddress;
-use std::env;
+use azalea_block::{Block, BlockState};
+use azalea_client::{
+    chat::ChatPacket, Account, Client, Event, JoinError, SprintDirection, WalkDirection,
+};
+use azalea_core::{
+    direction::Direction,
+    game_type::GameMode,
+    position::{BlockPos, Vec3},
+};
+use azalea_entity::{LookDirection, Physics, Position};
+use azalea_inventory::{operations::ClickType, ItemSlot};
+use azalea_protocol::{
+    packets::game::{
+        serverbound_client_command_packet::{
+            Action as ClientCommandAction, ServerboundClientCommandPacket,
+        },
+        serverbound_container_click_packet::ServerboundContainerClickPacket,
+        serverbound_container_close_packet::ServerboundContainerClosePacket,
+        serverbound_interact_packet::InteractionHand,
+        serverbound_player_command_packet::{
+            Action as PlayerCommandAction, ServerboundPlayerCommandPacket,
+        },
+        serverbound_use_item_on_packet::{BlockHit, ServerboundUseItemOnPacket},
+        ClientboundGamePacket,
+    },
+    resolver, ServerAddress,
+};
+use azalea_world::{InstanceName, MinecraftEntityId};
+use common::{
+    anvil::WorldSaver,
+    disconnect::{DisconnectCategory, DisconnectPhase},
+    driver::{BoxFuture, ConnectFailure, LoginDisconnect, Session},
+    entity::EntityTracker,
+    interact, movement, write_stdout_line, Args, BlockChange, ChatKind, ConnectStage,
+    InventorySlot, ItemStack, PositionReason, Signals, StdinEvent, StdinMessage, StdoutEvent,
+};
+use std::{collections::BTreeMap, ops::ControlFlow, process::ExitCode, time::Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
+
+/// チャンクデータのパレットの配列に長さが付いているか (1.21.5で無くなった)
+const CHUNK_DATA_LENGTH_PREFIXED: bool = true;
+
+/// ポートが省略されたときのポート
+const DEFAULT_PORT: u16 = 25565;
+
+/// このバージョンで使えないStdinEventの`type`とコマンドライン引数 (`hello`で報告する)
+const UNSUPPORTED: &[&str] = &[
+    // このバージョンのazaleaはプロキシに対応していない
+    "--proxy",
+    // このバージョンのazalea_blockにはブロックステートのプロパティを列挙する方法
+    // (0.14の`BlockTrait::property_map`) が無く、階段や原木の向きなどを全て既定の状態で
+    // 保存することになるので、ワールドの保存には対応しない
+    "--save-world",
+];
+
+/// `goto`の経路の決め方 (`hello`で報告する)
+// 0.9で書き直される前のazaleaの経路探索は、探索するノード数にも時間にも上限が無く
+// ECSのスレッドで探索するので、たどり着けない目的地を渡すとクライアントごと止まる
+// 走っている経路を止めるイベントも無く`stop`や`goto`の取り消しができないので、
+// このバージョンではまっすぐ歩くだけ
+const PATHFINDER: movement::Pathfinder = movement::Pathfinder::StraightLine;
 
-struct Args {
-    username: String,
-    host: String,
-    port: u16,
+#[tokio::main]
+async fn main() -> Result<ExitCode> {
+    common::driver::main::<Bot>(
+        env!("MC_VERSION"),
+        env!("AZALEA_REV"),
+        UNSUPPORTED,
+        PATHFINDER,
+    )
+    .await
 }
 
-impl Args {
-    fn parse() -> Result<Self> {
-        let args: Vec<String> = env::args().collect();
+/// サーバーに接続し、`connected`を書き出す
+async fn join(args: &Args) -> Result<(Client, UnboundedReceiver<Event>), ConnectError> {
+    let address = ServerAddress {
+        host: args.host.clone(),
+        port: args.port.unwrap_or(DEFAULT_PORT),
+    };
+    // `Client::join`は渡したアドレスをazalea自身で解決するので、同じ解決をして報告する
+    let resolved_address = resolver::resolve_address(&address)
+        .await
+        .map_err(|e| ConnectError::Resolve(e.to_string()))?;
+    let account = &Account::offline(&args.username);
+    // ハンドシェイクで送るホスト名は指定されたものにする
+    // (ポートを指定してもazaleaがSRVレコードを引くのは、このバージョンではそのまま)
+    let joined = Client::join(account, address.clone()).await?;
+    write_stdout_line(&StdoutEvent::Connected {
+        host: address.host,
+        port: address.port,
+        resolved_address: resolved_address.to_string(),
+    })
+    .map_err(JoinError::from)?;
+    Ok(joined)
+}
 
-        let port = args[3]
-            .parse::<u16>()
-            .map_err(|_| anyhow::anyhow!("Invalid port number: {}", args[3]))?;
+/// 接続できなかった理由
+enum ConnectError {
+    /// 接続先のアドレスを解決できなかった
+    Resolve(String),
+    Join(JoinError),
+}
 
-        Ok(Args {
-            username: args[1].clone(),
-            host: args[2].clone(),
-            port
-        })
+impl From<JoinError> for ConnectError {
+    fn from(e: JoinError) -> ConnectError {
+        ConnectError::Join(e)
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse()?;
-
-    let (_client, mut event) = Client::join(
-        &Account::offline(&args.username),
-        ServerAddress {
-            host: args.host,
-            port: args.port,
-        },
-    )
-    .await?;
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConnectError::Resolve(message) => write!(f, "failed to resolve address: {message}"),
+            ConnectError::Join(e) => e.fmt(f),
+        }
+    }
+}
+
+/// 接続の失敗を段階ごとに分ける
+fn connect_failure(e: ConnectError) -> ConnectFailure {
+    let (stage, disconnect) = match &e {
+        ConnectError::Resolve(_) => (ConnectStage::Resolve, None),
+        ConnectError::Join(JoinError::Resolver(_) | JoinError::InvalidAddress) => {
+            (ConnectStage::Resolve, None)
+        }
+        ConnectError::Join(JoinError::Connection(_) | JoinError::Io(_)) => {
+            (ConnectStage::Connect, None)
+        }
+        ConnectError::Join(JoinError::Disconnect { reason }) => (
+            ConnectStage::Rejected,
+            Some(LoginDisconnect {
+                json: Some(common::to_json_value(reason)),
+                text: Some(reason.to_string()),
+            }),
+        ),
+        _ => (ConnectStage::Handshake, None),
+    };
+    ConnectFailure {
+        stage,
+        message: e.to_string(),
+        disconnect,
+    }
+}
 
-    while let Some(e) = event.recv().await {
+/// 接続中のボット
+struct Bot {
+    client: Client,
+    events: UnboundedReceiver<Event>,
+    args: Args,
+    /// 次のティックで`position`を出力する理由
+    /// azaleaがパケットを反映し終わってから位置を読むために1ティック遅らせる
+    pending_position: Option<PositionReason>,
+    /// 最後に`periodic`の`position`を出力した時刻
+    last_position_report: Instant,
+    /// `walk`や`sprint`で残り何ティック動くか (`None`なら止めるまで)
+    move_ticks: Option<u32>,
+    /// `jump`でジャンプキーを押している
+    jumping: bool,
+    /// 実行中の`goto`
+    goto: Option<movement::Goto>,
+    /// 次のティックで`respawn`を出力する
+    pending_respawn: bool,
+    /// 最後に受け取ったウィンドウの`state_id` (`click_slot`で送り返す)
+    container_state_id: u32,
+    /// 開いているコンテナのウィンドウID
+    open_window: Option<i32>,
+    /// `entity_move`を絶対座標で出すためのエンティティの位置
+    entities: EntityTracker,
+    /// スポーンしたか (切断されたときの`phase`に使う)
+    spawned: bool,
+    /// 最後に切断されたときの理由の分類 (再接続するかの判断に使う)
+    disconnect_category: Option<DisconnectCategory>,
+    /// `quit`で要求された終了の理由
+    quit: Option<String>,
+}
+
+impl Session for Bot {
+    type Connection = (Client, UnboundedReceiver<Event>);
+
+    fn join(args: &Args) -> BoxFuture<'_, Result<Self::Connection, ConnectFailure>> {
+        Box::pin(async move { join(args).await.map_err(connect_failure) })
+    }
+
+    // このバージョンでは`--save-world`を受け付けないので`world_saver`は常に`None`
+    fn new(
+        args: Args,
+        _world_saver: Option<WorldSaver>,
+        (client, events): Self::Connection,
+    ) -> Self {
+        Bot {
+            client,
+            events,
+            args,
+            pending_position: None,
+            last_position_report: Instant::now(),
+            move_ticks: None,
+            jumping: false,
+            goto: None,
+            pending_respawn: false,
+            container_state_id: 0,
+            open_window: None,
+            entities: EntityTracker::default(),
+            spawned: false,
+            disconnect_category: None,
+            quit: None,
+        }
+    }
+
+    fn reconnect(&mut self, (client, events): Self::Connection) {
+        self.client = client;
+        self.events = events;
+        self.pending_position = None;
+        self.move_ticks = None;
+        self.jumping = false;
+        self.pending_respawn = false;
+        self.open_window = None;
+        self.entities.clear();
+        self.spawned = false;
+        self.disconnect_category = None;
+    }
+
+    fn args(&self) -> &Args {
+        &self.args
+    }
+
+    fn run<'a>(
+        &'a mut self,
+        stdin: &'a mut UnboundedReceiver<StdinMessage>,
+        signals: &'a mut Signals,
+    ) -> BoxFuture<'a, Result<Option<String>>> {
+        Box::pin(self.run_connected(stdin, signals))
+    }
+
+    fn disconnected(&mut self) -> Result<Option<DisconnectCategory>> {
+        if self.goto.take().is_some() {
+            write_stdout_line(&StdoutEvent::PathFailed {
+                reason: "disconnected".to_string(),
+            })?;
+        }
+        Ok(self.disconnect_category)
+    }
+
+    fn disconnect(&mut self) {
+        self.client.disconnect();
+    }
+}
+
+impl Bot {
+    /// 切断されるか終了を要求されるまでイベントと標準入力を処理する
+    /// 終了を要求されたらその理由を返す
+    async fn run_connected(
+        &mut self,
+        stdin: &mut UnboundedReceiver<StdinMessage>,
+        signals: &mut Signals,
+    ) -> Result<Option<String>> {
+        loop {
+            tokio::select! {
+                e = self.events.recv() => {
+                    let Some(e) = e else {
+                        // このバージョンのazaleaにはEvent::Disconnectが無いので
+                        // イベントチャンネルが閉じたことを切断として扱う
+                        self.disconnect_category =
+                            Some(common::write_disconnect_line(None, None, self.phase())?);
+                        break;
+                    };
+                    if self.handle_event(e)?.is_break() {
+                        break;
+                    }
+                }
+                message = stdin.recv() => {
+                    let Some(StdinMessage { id, event, .. }) = message else {
+                        return Ok(Some("stdin_closed".to_string()));
+                    };
+                    common::write_result_line(id, self.handle_stdin_event(event))?;
+                    if let Some(reason) = self.quit.take() {
+                        return Ok(Some(reason));
+                    }
+                }
+                reason = signals.recv() => return Ok(Some(reason)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// 今の接続の段階
+    fn phase(&self) -> DisconnectPhase {
+        if self.spawned {
+            DisconnectPhase::Game
+        } else {
+            DisconnectPhase::Login
+        }
+    }
+
+    /// azaleaのイベントをStdoutEventとして書き出す
+    /// 切断されたら`Break`を返す
+    fn handle_event(&mut self, e: Event) -> Result<ControlFlow<()>> {
         match e {
-            azalea_client::Event::Login => {
-                println!(r#"{{"event":"login"}}"#);
+            // このバージョンのazaleaにはEvent::Spawnが無いのでログイン完了をスポーンとして扱う
+            Event::Login => {
+                write_stdout_line(&StdoutEvent::Spawn {})?;
+                self.spawned = true;
+                self.pending_position = Some(PositionReason::Spawn);
+            }
+            Event::Tick => self.tick()?,
+            Event::Chat(chat) => {
+                let message = chat.message();
+                write_stdout_line(&StdoutEvent::Chat {
+                    kind: match &chat {
+                        ChatPacket::System(_) => ChatKind::System,
+                        ChatPacket::Player(_) => ChatKind::Player,
+                        #[allow(unreachable_patterns)]
+                        _ => ChatKind::Disguised,
+                    },
+                    sender_uuid: chat.sender_uuid().map(|x| x.to_string()),
+                    sender: chat.sender(),
+                    message: message.to_string(),
+                    json: common::to_json_value(&message),
+                })?;
+            }
+            Event::AddPlayer(info) => {
+                write_stdout_line(&StdoutEvent::PlayerJoin {
+                    player: player_info(&info),
+                })?;
+            }
+            Event::RemovePlayer(info) => {
+                write_stdout_line(&StdoutEvent::PlayerLeave {
+                    player: player_info(&info),
+                })?;
+            }
+            Event::UpdatePlayer(info) => {
+                write_stdout_line(&StdoutEvent::PlayerUpdate {
+                    player: player_info(&info),
+                })?;
+            }
+            Event::Packet(packet) => self.handle_packet(&packet)?,
+            _ => {}
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    fn handle_packet(&mut self, packet: &ClientboundGamePacket) -> Result<()> {
+        match packet {
+            ClientboundGamePacket::LevelChunkWithLight(packet) => {
+                let mut data = None;
+                if self.args.chunk_data {
+                    let (min_y, height) = {
+                        let world = self.client.world();
+                        let world = world.read();
+                        (world.chunks.min_y, world.chunks.height)
+                    };
+                    match common::chunk::read_sections(
+                        &packet.chunk_data.data[..],
+                        (height / 16) as usize,
+                        CHUNK_DATA_LENGTH_PREFIXED,
+                    ) {
+                        Ok(sections) => {
+                            if self.args.chunk_data {
+                                data = Some(common::chunk::chunk_data(min_y, height, &sections));
+                            }
+                        }
+                        Err(e) => eprintln!("failed to read chunk {} {}: {e}", packet.x, packet.z),
+                    }
+                }
+                write_stdout_line(&StdoutEvent::Chunk {
+                    x: packet.x,
+                    z: packet.z,
+                    data,
+                })?;
+            }
+            ClientboundGamePacket::PlayerPosition(_) => {
+                // スポーン直後の位置合わせはspawnとして報告する
+                if self.pending_position.is_none() {
+                    self.pending_position = Some(PositionReason::Teleport);
+                }
+            }
+            ClientboundGamePacket::OpenScreen(packet) => {
+                let window_id = i32::try_from(packet.container_id).unwrap_or(-1);
+                self.open_window = Some(window_id);
+                write_stdout_line(&StdoutEvent::ContainerOpen {
+                    window_id,
+                    kind: packet.menu_type.to_string(),
+                    title: packet.title.to_string(),
+                })?;
+            }
+            ClientboundGamePacket::ContainerClose(_) => {
+                self.open_window = None;
+            }
+            ClientboundGamePacket::ContainerSetContent(packet) => {
+                self.container_state_id = packet.state_id;
+                let window_id = i32::from(packet.container_id);
+                let items = inventory_slots(&packet.items);
+                if window_id == 0 {
+                    write_stdout_line(&StdoutEvent::Inventory { items })?;
+                } else {
+                    write_stdout_line(&StdoutEvent::ContainerContents { window_id, items })?;
+                }
+            }
+            ClientboundGamePacket::ContainerSetSlot(packet) => {
+                self.container_state_id = packet.state_id;
+                write_stdout_line(&StdoutEvent::SlotUpdate {
+                    window_id: i32::from(packet.container_id),
+                    slot: packet.slot,
+                    item: item_stack(&packet.item_stack),
+                })?;
+            }
+            ClientboundGamePacket::TabList(packet) => {
+                write_stdout_line(&StdoutEvent::TabList {
+                    header: packet.header.to_string(),
+                    footer: packet.footer.to_string(),
+                })?;
+            }
+            ClientboundGamePacket::SetHealth(packet) => {
+                write_stdout_line(&StdoutEvent::Health {
+                    health: packet.health,
+                    food: packet.food,
+                    saturation: packet.saturation,
+                })?;
+            }
+            ClientboundGamePacket::PlayerCombatKill(packet) => {
+                write_stdout_line(&StdoutEvent::Death {
+                    message: packet.message.to_string(),
+                })?;
+                if self.args.auto_respawn {
+                    self.respawn();
+                }
+            }
+            ClientboundGamePacket::Respawn(_) => {
+                // ディメンションはazaleaが反映してから読む
+                self.pending_respawn = true;
+            }
+            ClientboundGamePacket::ForgetLevelChunk(packet) => {
+                write_stdout_line(&StdoutEvent::ChunkUnload {
+                    x: packet.x,
+                    z: packet.z,
+                })?;
+            }
+            ClientboundGamePacket::BlockUpdate(packet) => {
+                write_stdout_line(&StdoutEvent::BlockUpdate {
+                    block: block_change(
+                        packet.pos.x,
+                        packet.pos.y,
+                        packet.pos.z,
+                        packet.block_state,
+                    ),
+                })?;
+            }
+            ClientboundGamePacket::SectionBlocksUpdate(packet) => {
+                let section = &packet.section_pos;
+                write_stdout_line(&StdoutEvent::MultiBlockUpdate {
+                    blocks: packet
+                        .states
+                        .iter()
+                        .map(|x| {
+                            block_change(
+                                section.x * 16 + x.pos.x as i32,
+                                section.y * 16 + x.pos.y as i32,
+                                section.z * 16 + x.pos.z as i32,
+                                x.state,
+                            )
+                        })
+                        .collect(),
+                })?;
+            }
+            _ => self.handle_entity_packet(packet)?,
+        }
+        Ok(())
+    }
+
+    /// エンティティ関係のパケットを`--entities`・`--entity-metadata`に応じて書き出す
+    fn handle_entity_packet(&mut self, packet: &ClientboundGamePacket) -> Result<()> {
+        if !self.args.entities && self.args.entity_metadata.is_none() {
+            return Ok(());
+        }
+        match packet {
+            ClientboundGamePacket::AddEntity(packet) => {
+                let position = [packet.x, packet.y, packet.z];
+                self.entity_spawn(
+                    entity_id(packet.id),
+                    packet.uuid.to_string(),
+                    packet.entity_type.to_string(),
+                    position,
+                )?;
+            }
+            // 1.20.2より前はプレイヤーだけ別のパケットでスポーンする
+            ClientboundGamePacket::AddPlayer(packet) => {
+                self.entity_spawn(
+                    entity_id(packet.id),
+                    packet.uuid.to_string(),
+                    "minecraft:player".to_string(),
+                    [packet.x, packet.y, packet.z],
+                )?;
+            }
+            ClientboundGamePacket::MoveEntityPos(packet) => {
+                let delta = &packet.delta;
+                self.entity_move_by(entity_id(packet.entity_id), [delta.xa, delta.ya, delta.za])?;
+            }
+            ClientboundGamePacket::MoveEntityPosRot(packet) => {
+                let delta = &packet.delta;
+                self.entity_move_by(entity_id(packet.entity_id), [delta.xa, delta.ya, delta.za])?;
+            }
+            ClientboundGamePacket::TeleportEntity(packet) => {
+                let position = packet.position;
+                self.entity_move_to(entity_id(packet.id), [position.x, position.y, position.z])?;
+            }
+            ClientboundGamePacket::RemoveEntities(packet) => {
+                let ids: Vec<i32> = packet.entity_ids.iter().map(|x| entity_id(*x)).collect();
+                for id in &ids {
+                    self.entities.remove(*id);
+                }
+                if self.args.entities {
+                    write_stdout_line(&StdoutEvent::EntityRemove { ids })?;
+                }
+            }
+            ClientboundGamePacket::SetEntityData(packet) => {
+                let Some(indices) = &self.args.entity_metadata else {
+                    return Ok(());
+                };
+                let values: BTreeMap<u8, String> = packet
+                    .packed_items
+                    .0
+                    .iter()
+                    .filter(|x| indices.contains(&x.index))
+                    .map(|x| (x.index, format!("{:?}", x.value)))
+                    .collect();
+                if !values.is_empty() {
+                    write_stdout_line(&StdoutEvent::EntityMetadata {
+                        id: entity_id(packet.id),
+                        values,
+                    })?;
+                }
             }
             _ => {}
         }
+        Ok(())
+    }
+
+    fn entity_spawn(
+        &mut self,
+        id: i32,
+        uuid: String,
+        kind: String,
+        position: [f64; 3],
+    ) -> Result<()> {
+        self.entities.set(id, position);
+        if self.args.entities {
+            let [x, y, z] = position;
+            write_stdout_line(&StdoutEvent::EntitySpawn {
+                id,
+                uuid,
+                kind,
+                x,
+                y,
+                z,
+            })?;
+        }
+        Ok(())
+    }
+
+    fn entity_move_to(&mut self, id: i32, position: [f64; 3]) -> Result<()> {
+        self.entities.set(id, position);
+        self.write_entity_move(id, position)
+    }
+
+    fn entity_move_by(&mut self, id: i32, delta: [i16; 3]) -> Result<()> {
+        match self.entities.move_by(id, delta) {
+            Some(position) => self.write_entity_move(id, position),
+            // スポーンを見ていないエンティティは位置が分からない
+            None => Ok(()),
+        }
+    }
+
+    fn write_entity_move(&self, id: i32, [x, y, z]: [f64; 3]) -> Result<()> {
+        if self.args.entities {
+            write_stdout_line(&StdoutEvent::EntityMove { id, x, y, z })?;
+        }
+        Ok(())
+    }
+
+    /// 保留中の`position`と`--position-interval-ms`の定期報告を出力する
+    fn tick(&mut self) -> Result<()> {
+        if self.pending_respawn {
+            self.pending_respawn = false;
+            write_stdout_line(&StdoutEvent::Respawn {
+                dimension: self.client.component::<InstanceName>().0.to_string(),
+            })?;
+        }
+        self.tick_movement();
+        self.tick_goto()?;
+        if let Some(reason) = self.pending_position.take() {
+            self.write_position(reason)?;
+        } else if let Some(interval) = self.args.position_interval {
+            if self.last_position_report.elapsed() >= interval {
+                self.write_position(PositionReason::Periodic)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// ジャンプキーを離し、`ticks`を指定した移動を数え終わったら止める
+    fn tick_movement(&mut self) {
+        if self.jumping {
+            self.client.set_jumping(false);
+            self.jumping = false;
+        }
+        if let Some(ticks) = &mut self.move_ticks {
+            // このティックの分を数えてから、残りが無ければ止める
+            *ticks = ticks.saturating_sub(1);
+            if *ticks == 0 {
+                self.client.walk(WalkDirection::None);
+                self.move_ticks = None;
+            }
+        }
+    }
+
+    /// `goto`の目的地に向かって1ティック分歩く
+    fn tick_goto(&mut self) -> Result<()> {
+        let Some(mut goto) = self.goto.take() else {
+            return Ok(());
+        };
+        let position = *self.client.component::<Position>();
+        let idle = false;
+        match goto.tick([position.x, position.y, position.z], idle) {
+            movement::GotoStep::Walk { steer, progress } => {
+                if let Some(steer) = steer {
+                    self.client.set_direction(steer.yaw, 0.0);
+                    self.client.walk(WalkDirection::Forward);
+                    if steer.jump {
+                        self.client.set_jumping(true);
+                        self.jumping = true;
+                    }
+                }
+                if let Some(remaining) = progress {
+                    write_stdout_line(&StdoutEvent::PathProgress {
+                        x: position.x,
+                        y: position.y,
+                        z: position.z,
+                        remaining,
+                    })?;
+                }
+                self.goto = Some(goto);
+            }
+            movement::GotoStep::Completed => {
+                self.stop_goto();
+                let [x, y, z] = goto.target;
+                write_stdout_line(&StdoutEvent::PathCompleted { x, y, z })?;
+            }
+            movement::GotoStep::Failed(reason) => {
+                self.stop_goto();
+                write_stdout_line(&StdoutEvent::PathFailed { reason })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `goto`のために歩いていたのを止める
+    fn stop_goto(&self) {
+        self.client.walk(WalkDirection::None);
+    }
+
+    /// 実行中の`goto`があれば`cancelled`として止める
+    fn cancel_goto(&mut self) -> Result<(), String> {
+        if self.goto.take().is_some() {
+            self.stop_goto();
+            write_stdout_line(&StdoutEvent::PathFailed {
+                reason: "cancelled".to_string(),
+            })
+            .map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+    fn write_position(&mut self, reason: PositionReason) -> Result<()> {
+        let position = *self.client.component::<Position>();
+        let look = self.client.component::<LookDirection>();
+        write_stdout_line(&StdoutEvent::Position {
+            reason,
+            x: position.x,
+            y: position.y,
+            z: position.z,
+            yaw: look.y_rot,
+            pitch: look.x_rot,
+            dimension: self.client.component::<InstanceName>().0.to_string(),
+            on_ground: self.client.component::<Physics>().on_ground,
+        })?;
+        self.last_position_report = Instant::now();
+        Ok(())
+    }
+
+    /// StdinEventをクライアントに反映する
+    fn handle_stdin_event(&mut self, event: StdinEvent) -> Result<(), String> {
+        match event {
+            StdinEvent::Chat { message } => {
+                common::validate_chat_message(&message)?;
+                self.client.chat(&message);
+            }
+            StdinEvent::Command { command } => {
+                let command = command.strip_prefix('/').unwrap_or(&command);
+                common::validate_chat_message(command)?;
+                self.client.send_command_packet(command);
+            }
+            StdinEvent::Look { yaw, pitch } => {
+                movement::validate_rotation(yaw, pitch)?;
+                self.client.set_direction(yaw, pitch);
+            }
+            StdinEvent::LookAt { x, y, z } => {
+                let position = *self.client.component::<Position>();
+                let (yaw, pitch) =
+                    movement::look_at_rotation([position.x, position.y, position.z], [x, y, z]);
+                movement::validate_rotation(yaw, pitch)?;
+                self.client.set_direction(yaw, pitch);
+            }
+            StdinEvent::Walk { direction, ticks } => {
+                movement::validate_ticks(ticks)?;
+                self.cancel_goto()?;
+                self.client.walk(walk_direction(direction));
+                self.move_ticks = ticks;
+            }
+            StdinEvent::Jump {} => {
+                self.client.set_jumping(true);
+                self.jumping = true;
+            }
+            StdinEvent::Sprint { ticks } => {
+                movement::validate_ticks(ticks)?;
+                self.cancel_goto()?;
+                self.client.sprint(SprintDirection::Forward);
+                self.move_ticks = ticks;
+            }
+            StdinEvent::Sneak { enabled } => self.sneak(enabled)?,
+            StdinEvent::Respawn {} => self.respawn(),
+            StdinEvent::Quit { reason } => {
+                self.quit = Some(reason.unwrap_or("quit".to_string()));
+            }
+            StdinEvent::UseBlock { x, y, z, face } => self.use_block(x, y, z, face),
+            StdinEvent::ClickSlot {
+                window_id,
+                slot,
+                button,
+                mode,
+            } => self.click_slot(window_id, slot, button, mode)?,
+            StdinEvent::CloseContainer {} => self.close_container()?,
+            StdinEvent::Goto { x, y, z, range } => {
+                let goto = movement::Goto::new([x, y, z], range, PATHFINDER)?;
+                self.cancel_goto()?;
+                self.move_ticks = None;
+                self.goto = Some(goto);
+            }
+            StdinEvent::Stop {} => {
+                self.cancel_goto()?;
+                self.client.walk(WalkDirection::None);
+                self.client.set_jumping(false);
+                self.move_ticks = None;
+                self.jumping = false;
+            }
+        }
+        Ok(())
     }
-    Ok(())
-}
\ No newline at end of file
+
+    /// ブロックの面をメインハンドで右クリックする
+    fn use_block(&self, x: i32, y: i32, z: i32, face: interact::BlockFace) {
+        let [cx, cy, cz] = face.center(x, y, z);
+        let block_hit = BlockHit {
+            block_pos: BlockPos::new(x, y, z),
+            direction: direction(face),
+            location: Vec3 {
+                x: cx,
+                y: cy,
+                z: cz,
+            },
+            inside: false,
+        };
+        self.client.write_packet(
+            ServerboundUseItemOnPacket {
+                hand: InteractionHand::MainHand,
+                block_hit,
+                sequence: 0,
+            }
+            .get(),
+        );
+    }
+
+    /// ウィンドウのスロットをクリックする
+    /// 変化するスロットの予測は送らず、サーバーからの再同期に任せる
+    fn click_slot(
+        &self,
+        window_id: i32,
+        slot: i16,
+        button: u8,
+        mode: interact::ClickMode,
+    ) -> Result<(), String> {
+        let container_id = window_id
+            .try_into()
+            .map_err(|_| "window_id is out of range".to_string())?;
+        self.client.write_packet(
+            ServerboundContainerClickPacket {
+                container_id,
+                state_id: self.container_state_id,
+                slot_num: slot,
+                button_num: button,
+                click_type: click_type(mode),
+                changed_slots: Default::default(),
+                carried_item: Default::default(),
+            }
+            .get(),
+        );
+        Ok(())
+    }
+
+    /// 開いているコンテナを閉じる
+    fn close_container(&mut self) -> Result<(), String> {
+        let window_id = self
+            .open_window
+            .take()
+            .ok_or_else(|| "no container is open".to_string())?;
+        let container_id = window_id
+            .try_into()
+            .map_err(|_| "window_id is out of range".to_string())?;
+        self.client
+            .write_packet(ServerboundContainerClosePacket { container_id }.get());
+        Ok(())
+    }
+
+    /// 死亡画面からのリスポーンを要求する
+    fn respawn(&self) {
+        self.client.write_packet(
+            ServerboundClientCommandPacket {
+                action: ClientCommandAction::PerformRespawn,
+            }
+            .get(),
+        );
+    }
+
+    /// スニークを切り替える
+    fn sneak(&mut self, enabled: bool) -> Result<(), String> {
+        // このバージョンのazaleaはスニークを扱わないので直接パケットを送る
+        let id = self.client.component::<MinecraftEntityId>().0;
+        self.client.write_packet(
+            ServerboundPlayerCommandPacket {
+                id,
+                action: if enabled {
+                    PlayerCommandAction::PressShiftKey
+                } else {
+                    PlayerCommandAction::ReleaseShiftKey
+                },
+                data: 0,
+            }
+            .get(),
+        );
+        Ok(())
+    }
+}
+
+/// StdinEventの歩く方向をazaleaのものにする
+fn walk_direction(direction: movement::WalkDirection) -> WalkDirection {
+    match direction {
+        movement::WalkDirection::Forward => WalkDirection::Forward,
+        movement::WalkDirection::Backward => WalkDirection::Backward,
+        movement::WalkDirection::Left => WalkDirection::Left,
+        movement::WalkDirection::Right => WalkDirection::Right,
+        movement::WalkDirection::ForwardLeft => WalkDirection::ForwardLeft,
+        movement::WalkDirection::ForwardRight => WalkDirection::ForwardRight,
+        movement::WalkDirection::BackwardLeft => WalkDirection::BackwardLeft,
+        movement::WalkDirection::BackwardRight => WalkDirection::BackwardRight,
+    }
+}
+
+/// タブリストのプレイヤーをStdoutEvent用にする
+fn player_info(info: &azalea_client::PlayerInfo) -> common::PlayerInfo {
+    common::PlayerInfo {
+        uuid: info.uuid.to_string(),
+        name: info.profile.name.clone(),
+        latency: info.latency,
+        gamemode: game_mode_name(info.gamemode).to_string(),
+        display_name: info.display_name.as_ref().map(|x| x.to_string()),
+    }
+}
+
+/// ゲームモードの`survival`のような名前
+fn game_mode_name(mode: GameMode) -> &'static str {
+    match mode {
+        GameMode::Survival => "survival",
+        GameMode::Creative => "creative",
+        GameMode::Adventure => "adventure",
+        GameMode::Spectator => "spectator",
+    }
+}
+
+/// パケットのエンティティIDを`i32`にする
+fn entity_id(id: u32) -> i32 {
+    id as i32
+}
+
+/// StdinEventのブロックの面をazaleaのものにする
+fn direction(face: interact::BlockFace) -> Direction {
+    match face {
+        interact::BlockFace::Down => Direction::Down,
+        interact::BlockFace::Up => Direction::Up,
+        interact::BlockFace::North => Direction::North,
+        interact::BlockFace::South => Direction::South,
+        interact::BlockFace::West => Direction::West,
+        interact::BlockFace::East => Direction::East,
+    }
+}
+
+/// StdinEventのクリックの種類をazaleaのものにする
+fn click_type(mode: interact::ClickMode) -> ClickType {
+    match mode {
+        interact::ClickMode::Pickup => ClickType::Pickup,
+        interact::ClickMode::QuickMove => ClickType::QuickMove,
+        interact::ClickMode::Swap => ClickType::Swap,
+        interact::ClickMode::Clone => ClickType::Clone,
+        interact::ClickMode::Throw => ClickType::Throw,
+        interact::ClickMode::QuickCraft => ClickType::QuickCraft,
+        interact::ClickMode::PickupAll => ClickType::PickupAll,
+    }
+}
+
+/// 空でないスロットをスロット番号付きで並べる
+fn inventory_slots(items: &[ItemSlot]) -> Vec<InventorySlot> {
+    items
+        .iter()
+        .enumerate()
+        .filter_map(|(slot, item)| {
+            Some(InventorySlot {
+                slot: slot as u16,
+                item: item_stack(item)?,
+            })
+        })
+        .collect()
+}
+
+/// azaleaのスロットの中身をStdoutEvent用にする (空なら`None`)
+fn item_stack(item: &ItemSlot) -> Option<ItemStack> {
+    let ItemSlot::Present(item) = item else {
+        return None;
+    };
+    Some(ItemStack {
+        id: item.kind.to_string(),
+        count: i32::from(item.count),
+        data: format!("{:?}", item.nbt),
+    })
+}
+
+/// ブロックステートから`block_update`などに載せる情報を作る
+fn block_change(x: i32, y: i32, z: i32, state: BlockState) -> BlockChange {
+    BlockChange {
+        x,
+        y,
+        z,
+        state: u32::from(state),
+        block: block_name(state),
+    }
+}
+
+/// `minecraft:stone`のようなブロック名
+fn block_name(state: BlockState) -> String {
+    format!("minecraft:{}", Box::<dyn Block>::from(state).id())
+}
@@ -0,0 +1,3 @@
+fn main() {
+    common::build::export_build_info();
+}